@@ -112,7 +112,7 @@ impl Default for MavEnumEntry {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct MavMessage {
-    pub id: u8,
+    pub id: u32,
     pub name: String,
     pub description: Option<String>,
     pub fields: Vec<MavField>,
@@ -834,6 +834,18 @@ impl MavProfile {
             .collect::<Vec<Tokens>>()
     }
 
+    /// Payload lengths needed for mavlink parsing
+    fn emit_msg_len(&self) -> Vec<Tokens> {
+        self.messages
+            .iter()
+            .map(|msg| {
+                let len = msg.fields.iter().fold(0, |sum, field| sum + field.mavtype.len());
+                let len = Ident::from(len.to_string());
+                quote!(#len)
+            })
+            .collect::<Vec<Tokens>>()
+    }
+
     fn emit_rust(&self) -> Tokens {
         let comment = self.emit_comments();
         let msgs = self.emit_msgs();
@@ -847,11 +859,15 @@ impl MavProfile {
         
         let msg_ids = self.emit_msg_ids();
         let msg_crc = self.emit_msg_crc();
+        let len_msg_ids = msg_ids.clone();
+        let msg_len = self.emit_msg_len();
         let mav_message = self.emit_mav_message(enum_names.clone(), struct_names.clone());
         let mav_message_parse =
             self.emit_mav_message_parse(enum_names.clone(), struct_names.clone(), msg_ids.clone());
         let mav_message_id = self.emit_mav_message_id(enum_names.clone(), msg_ids.clone());
         let mav_message_serialize = self.emit_mav_message_serialize(enum_names);
+        let mav_message_target_system = self.emit_mav_message_target("target_system");
+        let mav_message_target_component = self.emit_mav_message_target("target_component");
         let protobuf_msg_tags = self.emit_msg_tags();
         let protobuf_msg_set = self.emit_msg_set();
 
@@ -907,12 +923,20 @@ impl MavProfile {
                 #mav_message_parse
                 #mav_message_id
                 #mav_message_serialize
-                pub fn extra_crc(id: u8) -> u8 {
+                #mav_message_target_system
+                #mav_message_target_component
+                pub fn extra_crc(id: u32) -> u8 {
                     match id {
                         #(#msg_ids => #msg_crc,)*
                         _ => 0,
                     }
                 }
+                pub fn payload_len(id: u32) -> Option<usize> {
+                    match id {
+                        #(#len_msg_ids => Some(#msg_len),)*
+                        _ => None,
+                    }
+                }
             }
             // End of mavlink only part
 
@@ -998,7 +1022,7 @@ impl MavProfile {
         ids: Vec<Tokens>,
     ) -> Tokens {
         quote!{
-            pub fn parse(id: u8, payload: &[u8]) -> Option<MavMessage> {
+            pub fn parse(id: u32, payload: &[u8]) -> Option<MavMessage> {
                 match id {
                     #(#ids => Some(MavMessage::#enums(#structs::parse(payload))),)*
                     _ => None,
@@ -1009,7 +1033,7 @@ impl MavProfile {
 
     fn emit_mav_message_id(&self, enums: Vec<Tokens>, ids: Vec<Tokens>) -> Tokens {
         quote!{
-            pub fn message_id(&self) -> u8 {
+            pub fn message_id(&self) -> u32 {
                 match self {
                     #(MavMessage::#enums(..) => #ids,)*
                 }
@@ -1017,6 +1041,30 @@ impl MavProfile {
         }
     }
 
+    /// Accessor for a `target_system` or `target_component` field, for the messages that have it
+    fn emit_mav_message_target(&self, field_name: &str) -> Tokens {
+        let enums = self.messages
+            .iter()
+            .filter(|msg| msg.fields.iter().any(|field| field.name == field_name))
+            .map(|msg| {
+                let name = Ident::from(msg.name.clone());
+                quote!(#name)
+            })
+            .collect::<Vec<Tokens>>();
+        let fn_name = Ident::from(field_name);
+        let field = Ident::from(field_name);
+        let fields = vec![field; enums.len()];
+        quote!{
+            #[allow(unreachable_patterns)]
+            pub fn #fn_name(&self) -> Option<u8> {
+                match self {
+                    #(&MavMessage::#enums(ref body) => Some(body.#fields as u8),)*
+                    _ => None,
+                }
+            }
+        }
+    }
+
     fn emit_mav_message_serialize(&self, enums: Vec<Tokens>) -> Tokens {
         quote!{
             pub fn serialize(&self) -> Vec<u8> {
@@ -1139,7 +1187,7 @@ pub fn parse_profile(file: &mut Read) -> MavProfile {
                                     message.name = attr.value.clone();
                                 }
                                 "id" => {
-                                    message.id = attr.value.parse::<u8>().unwrap();
+                                    message.id = attr.value.parse::<u32>().unwrap();
                                 }
                                 _ => (),
                             }
@@ -0,0 +1,598 @@
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_serial;
+
+use common::MavMessage;
+use {write_signed, FrameDecoder, Header, MavFrame, MavlinkVersion, ReceiveTime, SigningConfig, SigningData};
+use connection::{parse_address, resolve, SerialConfig, UdpMode, UdpPeer, UdpRoute, DEFAULT_DETECT_TIMEOUT};
+use error::ReadErrorCounts;
+
+/// Tokio codec for MAVLink frames.
+///
+/// Decoding yields every valid frame, stamped with the time the bytes completing it were
+/// decoded, and skips frames that cannot be decoded, counting them in `read_error_counts`.
+/// Encoding frames each message with the protocol version and header of the `MavFrame`
+/// it is given.
+pub struct MavlinkCodec {
+    decoder: FrameDecoder,
+    signing: Option<SigningData>,
+    errors: ReadErrorCounts,
+    last_read: ReceiveTime,
+}
+
+impl MavlinkCodec {
+    pub fn new() -> MavlinkCodec {
+        MavlinkCodec {
+            decoder: FrameDecoder::new(),
+            signing: None,
+            errors: ReadErrorCounts::default(),
+            last_read: ReceiveTime::now(),
+        }
+    }
+
+    /// Configure MAVLink 2 message signing, or disable it with `None`
+    pub fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.signing = signing.map(SigningData::from_config);
+    }
+
+    /// Number of received frames skipped so far, by reason
+    pub fn read_error_counts(&self) -> ReadErrorCounts {
+        self.errors
+    }
+
+    fn next_frame(&mut self) -> Option<MavFrame> {
+        while let Some(result) = self.decoder.next_frame(self.signing.as_ref()) {
+            match result {
+                Ok(mut frame) => {
+                    frame.received = Some(self.last_read);
+                    return Some(frame);
+                }
+                Err(e) => self.errors.record(&e),
+            }
+        }
+        None
+    }
+}
+
+impl Decoder for MavlinkCodec {
+    type Item = MavFrame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<MavFrame>> {
+        if !src.is_empty() {
+            let len = src.len();
+            self.last_read = ReceiveTime::now();
+            self.decoder.push(&src.split_to(len));
+        }
+        Ok(self.next_frame())
+    }
+}
+
+impl Encoder for MavlinkCodec {
+    type Item = MavFrame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: MavFrame, dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, frame.version, frame.header, &frame.msg, self.signing.as_ref()));
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+/// An asynchronous MAVLink connection.
+///
+/// Received frames are read from the connection as a `Stream`, and messages are sent by
+/// writing them to the connection as a `Sink`.
+pub trait AsyncMavConnection:
+    Stream<Item = MavFrame, Error = io::Error> + Sink<SinkItem = MavMessage, SinkError = io::Error> + Send
+{
+    /// Set the MAVLink version used to frame outgoing messages
+    fn set_protocol_version(&mut self, version: MavlinkVersion);
+
+    /// Get the MAVLink version used to frame outgoing messages
+    fn get_protocol_version(&self) -> MavlinkVersion;
+
+    /// Set the system and component id of outgoing messages (255 and 0 by default)
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8);
+
+    /// Get the system and component id of outgoing messages
+    fn get_source_ids(&self) -> (u8, u8);
+
+    /// Configure MAVLink 2 message signing, or disable it with `None`
+    fn setup_signing(&mut self, signing: Option<SigningConfig>);
+
+    /// Number of received frames skipped so far, by reason
+    fn read_error_counts(&self) -> ReadErrorCounts;
+
+    /// Start sending a frame with an explicit header and protocol version, as the `Sink`
+    /// does for messages. Complete it with `poll_complete`.
+    fn start_send_frame(&mut self, frame: MavFrame) -> StartSend<MavFrame, io::Error>;
+}
+
+/// Connect asynchronously to a MAVLink node by address string.
+///
+/// Accepts `tcp`, `udpin`, `udpout` and `serial` addresses in the same format as `connect`,
+/// along with the `sysid` and `compid` query parameters. Must be run within a tokio runtime.
+pub fn connect_async(address: &str) -> Box<Future<Item = Box<AsyncMavConnection>, Error = io::Error> + Send> {
+    let (address, options) = match parse_address(address) {
+        Ok(parsed) => parsed,
+        Err(e) => return Box::new(::futures::future::err(e)),
+    };
+    if options.interface.is_some() {
+        return Box::new(::futures::future::err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The iface parameter is only supported by udpmcast",
+        )));
+    }
+    if options.speed.is_some() {
+        return Box::new(::futures::future::err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The speed parameter is only supported by file",
+        )));
+    }
+    let set_source_ids = move |mut conn: Box<AsyncMavConnection>| {
+        let (system_id, component_id) = conn.get_source_ids();
+        conn.set_source_ids(
+            options.system_id.unwrap_or(system_id),
+            options.component_id.unwrap_or(component_id),
+        );
+        conn
+    };
+
+    let result = if address.starts_with("tcp:") {
+        let addr = match resolve(&address["tcp:".len()..]) {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(::futures::future::err(e)),
+        };
+        return Box::new(TcpStream::connect(&addr).map(move |socket| {
+            set_source_ids(Box::new(AsyncTcp::new(socket)))
+        }));
+    } else if address.starts_with("udpin:") {
+        AsyncUdp::udpin(&address["udpin:".len()..]).map(|c| Box::new(c) as Box<AsyncMavConnection>)
+    } else if address.starts_with("udpout:") {
+        AsyncUdp::udpout(&address["udpout:".len()..]).map(|c| Box::new(c) as Box<AsyncMavConnection>)
+    } else if address.starts_with("serial:") {
+        AsyncSerial::open(&address["serial:".len()..]).map(|c| Box::new(c) as Box<AsyncMavConnection>)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "Prefix must be one of udpin, udpout, tcp or serial",
+        ))
+    };
+    Box::new(::futures::future::result(result.map(set_source_ids)))
+}
+
+/// Asynchronous MAVLink connection over a byte stream such as TCP or a serial port
+pub struct AsyncStream<T> {
+    framed: Framed<T, MavlinkCodec>,
+    sequence: u8,
+    protocol_version: MavlinkVersion,
+    system_id: u8,
+    component_id: u8,
+}
+
+/// Asynchronous TCP MAVLink connection
+pub type AsyncTcp = AsyncStream<TcpStream>;
+
+/// Asynchronous serial MAVLink connection
+pub type AsyncSerial = AsyncStream<tokio_serial::Serial>;
+
+impl<T: AsyncRead + AsyncWrite> AsyncStream<T> {
+    pub fn new(io: T) -> AsyncStream<T> {
+        AsyncStream {
+            framed: Framed::new(io, MavlinkCodec::new()),
+            sequence: 0,
+            protocol_version: MavlinkVersion::V1,
+            system_id: 255,
+            component_id: 0,
+        }
+    }
+}
+
+impl AsyncSerial {
+    /// Open a serial port with settings as described by `SerialConfig`, or the port found
+    /// by `SerialConfig::detect` for `auto`.
+    ///
+    /// Detection blocks the calling thread.
+    pub fn open(settings: &str) -> io::Result<AsyncSerial> {
+        let config = if settings == "auto" {
+            try!(SerialConfig::detect(DEFAULT_DETECT_TIMEOUT))
+        } else {
+            try!(SerialConfig::parse(settings))
+        };
+        let mut port_settings = tokio_serial::SerialPortSettings::default();
+        port_settings.baud_rate = config.baud as u32;
+        port_settings.data_bits = match config.char_size {
+            ::serial::Bits5 => tokio_serial::DataBits::Five,
+            ::serial::Bits6 => tokio_serial::DataBits::Six,
+            ::serial::Bits7 => tokio_serial::DataBits::Seven,
+            ::serial::Bits8 => tokio_serial::DataBits::Eight,
+        };
+        port_settings.parity = match config.parity {
+            ::serial::ParityNone => tokio_serial::Parity::None,
+            ::serial::ParityOdd => tokio_serial::Parity::Odd,
+            ::serial::ParityEven => tokio_serial::Parity::Even,
+        };
+        port_settings.stop_bits = match config.stop_bits {
+            ::serial::Stop1 => tokio_serial::StopBits::One,
+            ::serial::Stop2 => tokio_serial::StopBits::Two,
+        };
+        port_settings.flow_control = match config.flow_control {
+            ::serial::FlowNone => tokio_serial::FlowControl::None,
+            ::serial::FlowSoftware => tokio_serial::FlowControl::Software,
+            ::serial::FlowHardware => tokio_serial::FlowControl::Hardware,
+        };
+        let port = try!(tokio_serial::Serial::from_path(&config.port, &port_settings).map_err(|e| {
+            io::Error::new(e.kind(), format!("Could not open serial port {}: {}", config.port, e))
+        }));
+        Ok(AsyncStream::new(port))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for AsyncStream<T> {
+    type Item = MavFrame;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<MavFrame>, io::Error> {
+        self.framed.poll()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for AsyncStream<T> {
+    type SinkItem = MavMessage;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: MavMessage) -> StartSend<MavMessage, io::Error> {
+        let frame = MavFrame {
+            header: Header {
+                sequence: self.sequence,
+                system_id: self.system_id,
+                component_id: self.component_id,
+            },
+            version: self.protocol_version,
+            msg: msg,
+            received: None,
+        };
+        match try!(self.framed.start_send(frame)) {
+            AsyncSink::Ready => {
+                self.sequence = self.sequence.wrapping_add(1);
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(frame) => Ok(AsyncSink::NotReady(frame.msg)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.framed.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.framed.close()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send> AsyncMavConnection for AsyncStream<T> {
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.protocol_version = version;
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.protocol_version
+    }
+
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+        self.system_id = system_id;
+        self.component_id = component_id;
+    }
+
+    fn get_source_ids(&self) -> (u8, u8) {
+        (self.system_id, self.component_id)
+    }
+
+    fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.framed.codec_mut().setup_signing(signing);
+    }
+
+    fn read_error_counts(&self) -> ReadErrorCounts {
+        self.framed.codec().read_error_counts()
+    }
+
+    fn start_send_frame(&mut self, frame: MavFrame) -> StartSend<MavFrame, io::Error> {
+        self.framed.start_send(frame)
+    }
+}
+
+/// Asynchronous UDP MAVLink connection.
+///
+/// Peers are tracked as by `Udp`: in server (`udpin`) mode messages with a target system
+/// go to the peer that system was last heard from, and all other messages to every peer.
+pub struct AsyncUdp {
+    socket: UdpSocket,
+    route: UdpRoute,
+    codec: MavlinkCodec,
+    recv_buf: Vec<u8>,
+    /// Sender of the datagram being decoded, in server mode
+    source: Option<SocketAddr>,
+    /// Datagram waiting for the socket to become writable, with the peers it has not been
+    /// sent to yet
+    pending: Option<(Vec<u8>, Vec<SocketAddr>)>,
+    sequence: u8,
+    protocol_version: MavlinkVersion,
+    system_id: u8,
+    component_id: u8,
+}
+
+impl AsyncUdp {
+    fn new(socket: UdpSocket, mode: UdpMode, dest: Option<SocketAddr>) -> AsyncUdp {
+        AsyncUdp {
+            socket: socket,
+            route: UdpRoute::new(mode, dest),
+            codec: MavlinkCodec::new(),
+            recv_buf: vec![0; 65536],
+            source: None,
+            pending: None,
+            sequence: 0,
+            protocol_version: MavlinkVersion::V1,
+            system_id: 255,
+            component_id: 0,
+        }
+    }
+
+    pub fn udpin(address: &str) -> io::Result<AsyncUdp> {
+        let addr = try!(resolve(address));
+        let socket = try!(UdpSocket::bind(&addr));
+        Ok(AsyncUdp::new(socket, UdpMode::Server, None))
+    }
+
+    pub fn udpout(address: &str) -> io::Result<AsyncUdp> {
+        let addr = try!(resolve(address));
+        let socket = try!(UdpSocket::bind(&SocketAddr::from_str("0.0.0.0:0").unwrap()));
+        Ok(AsyncUdp::new(socket, UdpMode::Client, Some(addr)))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Peers currently known to a server, empty in client mode
+    pub fn peers(&self) -> Vec<UdpPeer> {
+        let timeout = self.route.peers.timeout;
+        self.route
+            .peers
+            .peers
+            .iter()
+            .filter(|peer| peer.last_seen.elapsed() < timeout)
+            .cloned()
+            .collect()
+    }
+
+    /// Set how long a server keeps a peer that sends nothing
+    pub fn set_peer_timeout(&mut self, timeout: Duration) {
+        self.route.peers.timeout = timeout;
+    }
+}
+
+impl Stream for AsyncUdp {
+    type Item = MavFrame;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<MavFrame>, io::Error> {
+        loop {
+            if let Some(frame) = self.codec.next_frame() {
+                if let Some(src) = self.source {
+                    self.route.peers.learn(src, frame.header.system_id);
+                }
+                return Ok(Async::Ready(Some(frame)));
+            }
+            let (len, src) = try_ready!(self.socket.poll_recv_from(&mut self.recv_buf));
+            // frames never span datagrams
+            self.codec.last_read = ReceiveTime::now();
+            self.codec.decoder.clear();
+            if !self.route.received(src, &self.recv_buf[..len], self.codec.last_read.monotonic) {
+                continue;
+            }
+            if self.route.mode == UdpMode::Server {
+                self.source = Some(src);
+            }
+            self.codec.decoder.push(&self.recv_buf[..len]);
+        }
+    }
+}
+
+impl Sink for AsyncUdp {
+    type SinkItem = MavMessage;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, msg: MavMessage) -> StartSend<MavMessage, io::Error> {
+        let frame = MavFrame {
+            header: Header {
+                sequence: self.sequence,
+                system_id: self.system_id,
+                component_id: self.component_id,
+            },
+            version: self.protocol_version,
+            msg: msg,
+            received: None,
+        };
+        match try!(self.start_send_frame(frame)) {
+            AsyncSink::Ready => {
+                self.sequence = self.sequence.wrapping_add(1);
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(frame) => Ok(AsyncSink::NotReady(frame.msg)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if let Some((ref buf, ref mut destinations)) = self.pending {
+            while let Some(&addr) = destinations.first() {
+                try_ready!(self.socket.poll_send_to(buf, &addr));
+                destinations.remove(0);
+            }
+        }
+        self.pending = None;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl AsyncMavConnection for AsyncUdp {
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.protocol_version = version;
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.protocol_version
+    }
+
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+        self.system_id = system_id;
+        self.component_id = component_id;
+    }
+
+    fn get_source_ids(&self) -> (u8, u8) {
+        (self.system_id, self.component_id)
+    }
+
+    fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.codec.setup_signing(signing);
+    }
+
+    fn read_error_counts(&self) -> ReadErrorCounts {
+        self.codec.read_error_counts()
+    }
+
+    fn start_send_frame(&mut self, frame: MavFrame) -> StartSend<MavFrame, io::Error> {
+        if self.pending.is_some() {
+            try!(self.poll_complete());
+            if self.pending.is_some() {
+                return Ok(AsyncSink::NotReady(frame));
+            }
+        }
+
+        let destinations = self.route.destinations(frame.msg.target_system());
+        if !destinations.is_empty() {
+            let mut buf = Vec::new();
+            try!(write_signed(&mut buf, frame.version, frame.header, &frame.msg, self.codec.signing.as_ref()));
+            self.route.sending(&buf);
+            self.pending = Some((buf, destinations));
+        }
+        Ok(AsyncSink::Ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::HEARTBEAT_DATA;
+    use futures::future;
+    use std::net;
+    use tokio::runtime::current_thread::Runtime;
+    use write_versioned;
+
+    fn heartbeat() -> MavMessage {
+        MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())
+    }
+
+    /// COMMAND_LONG addressed to `target_system`
+    fn command(target_system: u8) -> MavMessage {
+        let mut payload = [0; 33];
+        payload[30] = target_system;
+        MavMessage::parse(76, &payload).unwrap()
+    }
+
+    fn frame(version: MavlinkVersion, sequence: u8, msg: MavMessage) -> MavFrame {
+        MavFrame {
+            header: Header {
+                sequence: sequence,
+                system_id: 1,
+                component_id: 1,
+            },
+            version: version,
+            msg: msg,
+            received: None,
+        }
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let frames = vec![
+            frame(MavlinkVersion::V1, 1, heartbeat()),
+            frame(MavlinkVersion::V2, 2, heartbeat()),
+            frame(MavlinkVersion::V2, 3, command(7)),
+        ];
+        let mut codec = MavlinkCodec::new();
+        let mut bytes = BytesMut::new();
+        for frame in frames.iter() {
+            codec.encode(frame.clone(), &mut bytes).unwrap();
+        }
+
+        // in small chunks, as from a serial port
+        let mut decoded = Vec::new();
+        let mut codec = MavlinkCodec::new();
+        for chunk in bytes.chunks(5) {
+            let mut src = BytesMut::from(chunk);
+            while let Some(mut frame) = codec.decode(&mut src).unwrap() {
+                assert!(frame.received.take().is_some());
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+        assert_eq!(codec.read_error_counts(), ReadErrorCounts::default());
+    }
+
+    /// Socket of a node sending a HEARTBEAT from `system_id` to `server`
+    fn peer(server: SocketAddr, system_id: u8) -> net::UdpSocket {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let header = Header {
+            sequence: 0,
+            system_id: system_id,
+            component_id: 1,
+        };
+        let mut buf = Vec::new();
+        write_versioned(&mut buf, MavlinkVersion::V1, header, &heartbeat()).unwrap();
+        socket.send_to(&buf, server).unwrap();
+        socket
+    }
+
+    /// Message id of the datagram waiting on `socket`, if one arrives
+    fn received_msgid(socket: &net::UdpSocket) -> Option<u32> {
+        let mut buf = [0; 300];
+        socket.recv(&mut buf).ok().map(|_| ::read(&mut &buf[..]).unwrap().1.message_id())
+    }
+
+    #[test]
+    fn udp_server_routes_to_peers() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut server = AsyncUdp::udpin("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let one = peer(addr, 1);
+        let two = peer(addr, 2);
+        for &system_id in [1, 2].iter() {
+            let (frame, rest) = runtime.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+            assert_eq!(frame.unwrap().header.system_id, system_id);
+            server = rest;
+        }
+        assert_eq!(server.peers().len(), 2);
+
+        // targeted messages go to the peer of the target system only
+        server = runtime.block_on(server.send(command(2))).unwrap();
+        assert_eq!(received_msgid(&two), Some(76));
+        assert_eq!(received_msgid(&one), None);
+
+        // others go to every peer, also when sent as frames
+        server.start_send_frame(frame(MavlinkVersion::V2, 9, heartbeat())).unwrap();
+        runtime.block_on(future::poll_fn(|| server.poll_complete())).unwrap();
+        assert_eq!(received_msgid(&one), Some(0));
+        assert_eq!(received_msgid(&two), Some(0));
+    }
+}
@@ -6,7 +6,6 @@ extern crate clap;
 #[cfg(feature = "json")]
 extern crate serde_json;
 
-use std::sync::Arc;
 use std::thread;
 use std::process::exit;
 
@@ -32,16 +31,66 @@ fn main() {
     let yaml = load_yaml!("../../cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    let device = matches.value_of("MAVLINK_DEVICE").unwrap();
+    let mut device = matches.value_of("MAVLINK_DEVICE").unwrap().to_string();
+    if device == "serial:auto" || device.starts_with("serial:auto?") {
+        // detect once up front, so we can report what was found
+        let query = device["serial:auto".len()..].to_string();
+        match mavlink_proto::SerialConfig::detect(mavlink_proto::DEFAULT_DETECT_TIMEOUT) {
+            Ok(config) => {
+                println!("Found Mavlink device {}", config);
+                device = format!("serial:{}{}", config, query);
+            }
+            Err(e) => {
+                println!("Serial auto-detection failed: {}", e);
+                exit(1);
+            }
+        }
+    }
     println!("Mavlink connecting to {}", device);
-    let vehicle = Arc::new(mavlink_proto::connect(device).unwrap());
+    let mut builder = mavlink_proto::ConnectionBuilder::new(&device).reconnect(matches.is_present("reconnect"));
+    if let Some(rate) = matches.value_of("max_rate") {
+        match rate.parse() {
+            Ok(bytes_per_sec) => builder = builder.max_rate(bytes_per_sec),
+            Err(_) => {
+                println!("Invalid rate {}, expected bytes per second", rate);
+                exit(1);
+            }
+        }
+    }
+    let mut vehicle = builder.connect().unwrap();
+    if matches.is_present("mavlink2") {
+        vehicle.set_protocol_version(mavlink_proto::MavlinkVersion::V2);
+    }
+    if let Some(passphrase) = matches.value_of("signing_key") {
+        let key = mavlink_proto::SigningConfig::key_from_passphrase(passphrase);
+        vehicle.set_protocol_version(mavlink_proto::MavlinkVersion::V2);
+        vehicle.setup_signing(Some(mavlink_proto::SigningConfig::new(key, 0, true, false)));
+    }
+    if matches.is_present("debug") {
+        vehicle.set_error_handler(Some(Box::new(|e| println!("Skipped frame: {}", e))));
+    }
+    // share the Mavlink device with other ground stations through a router
+    let vehicle: mavlink_proto::Connection = match matches.values_of("route") {
+        Some(addresses) => {
+            let mut router = mavlink_proto::Router::new();
+            router.add_link(vehicle);
+            for address in addresses {
+                println!("Routing to {}", address);
+                router.add_link(mavlink_proto::connect(address).unwrap());
+            }
+            let local = router.local_link();
+            router.start();
+            Box::new(local)
+        }
+        None => vehicle,
+    };
+    let (sender, receiver) = mavlink_proto::split(vehicle);
     let context = zmq::Context::new();
 
     // Protobuf RX thread
     // Receives protobuf messages from UxAS/OpenAMASE and sends
     // them as Mavlink messages to the Mavlink device
     thread::spawn({
-        let vehicle = vehicle.clone();
         let subscriber = context.socket(zmq::SUB).unwrap();
         let filter = "";
         let addr = matches.value_of("ADDR_SUB").unwrap();
@@ -64,7 +113,7 @@ fn main() {
                 let stream = subscriber.recv_string(0).unwrap().unwrap();
                 println!("Received msg = {}", stream);
                 let msg: MavMessage = serde_json::from_str(&stream).unwrap();
-                vehicle.send(&msg).ok();
+                sender.send(&msg).ok();
             }
 
             #[cfg(not(feature = "json"))]
@@ -72,8 +121,10 @@ fn main() {
                 let stream = subscriber.recv_bytes(0).unwrap();
                 println!("Received {} bytes", stream.len());
                 let msg = MavMessage::from_proto_msg(stream).unwrap();
-                vehicle.send(&msg).unwrap();
-                println!("Sent data");
+                match sender.send(&msg) {
+                    Ok(()) => println!("Sent data"),
+                    Err(e) => println!("Send error: {}", e),
+                }
             }
         }
     });
@@ -94,9 +145,10 @@ fn main() {
     }
 
     loop {
-        if let Ok(msg) = vehicle.recv() {
+        if let Ok(frame) = receiver.recv_frame() {
+            let msg = frame.msg;
             if matches.is_present("debug") {
-                println!("{:?}", msg);
+                println!("{:?}: {:?}", frame.header, msg);
             }
 
             #[cfg(not(feature = "json"))]
@@ -1,23 +1,123 @@
 use common::MavMessage;
-use {read, write, Header};
+use {read_frame, write_signed, FrameDecoder, Header, MavFrame, MavlinkVersion, ReceiveTime, SigningConfig, SigningData};
+use error::{MessageReadError, ReadErrorCounts, RecvTimeoutError};
+use reconnect::ReconnectingConnection;
+use tlog::TlogFile;
+use throttle::ThrottledConnection;
+use stats::{LinkStats, StatsTracker};
 
-use std::sync::Mutex;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
-use std::io::{self, Read};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::cmp;
+use std::fmt;
 
 use std::str::FromStr;
 
+use net2::{UdpBuilder, UdpSocketExt};
 use serial::SerialPort;
 
+#[cfg(unix)]
+use std::ffi::{CStr, OsStr};
+#[cfg(unix)]
+use std::fs::{self, File, OpenOptions};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::{symlink, FileTypeExt, OpenOptionsExt};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use libc;
+
+/// Default system id of outgoing messages, the one used by ground control stations
+pub const DEFAULT_SYSTEM_ID: u8 = 255;
+
+/// Default component id of outgoing messages
+pub const DEFAULT_COMPONENT_ID: u8 = 0;
+
+/// A connection of any type, as returned by `connect`
+pub type Connection = Box<MavConnection + Sync + Send>;
+
+/// Callback invoked for every received frame that is skipped because it cannot be decoded
+pub type ErrorHandler = Box<Fn(&MessageReadError) + Send + Sync>;
+
 /// A MAVLink connection
 pub trait MavConnection {
     /// Receive a mavlink message.
     ///
-    /// Blocks until a valid frame is received, ignoring invalid messages.
-    fn recv(&self) -> io::Result<MavMessage>;
+    /// Blocks until a valid frame is received, skipping invalid frames. Skipped frames are
+    /// counted in `read_error_counts` and passed to the error handler, if one is set.
+    fn recv(&self) -> io::Result<MavMessage> {
+        self.recv_frame().map(|frame| frame.msg)
+    }
+
+    /// Receive a mavlink message together with its header and protocol version.
+    ///
+    /// Blocks until a valid frame is received, skipping invalid frames like `recv`. The
+    /// frame carries the time at which the bytes completing it were read from the link.
+    fn recv_frame(&self) -> io::Result<MavFrame>;
+
+    /// Receive a mavlink frame, waiting at most `timeout` for it.
+    ///
+    /// Skips invalid frames like `recv`. Returns `RecvTimeoutError::Timeout` if no valid
+    /// frame arrived in time.
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError>;
+
+    /// Receive a mavlink frame if one is available, without waiting for the link.
+    ///
+    /// Frames already buffered are returned first; otherwise the link is read only if
+    /// data is waiting on it. Returns `RecvTimeoutError::Timeout` if no complete frame
+    /// has arrived.
+    fn try_recv(&self) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_timeout(Duration::from_secs(0))
+    }
 
     /// Send a mavlink message
     fn send(&self, data: &MavMessage) -> io::Result<()>;
+
+    /// Send a mavlink message with an explicit header and protocol version.
+    ///
+    /// The header is sent as given, so forwarded frames keep the identity and sequence
+    /// number of their sender. Outgoing signing still applies.
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()>;
+
+    /// Set the system and component id of outgoing messages (255 and 0 by default)
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8);
+
+    /// Get the system and component id of outgoing messages
+    fn get_source_ids(&self) -> (u8, u8);
+
+    /// Set the MAVLink version used to frame outgoing messages
+    fn set_protocol_version(&mut self, version: MavlinkVersion);
+
+    /// Get the MAVLink version used to frame outgoing messages
+    fn get_protocol_version(&self) -> MavlinkVersion;
+
+    /// Configure MAVLink 2 message signing, or disable it with `None`.
+    ///
+    /// Once configured, incoming frames are verified and filtered according to the
+    /// signing configuration, and outgoing MAVLink 2 frames are signed if requested.
+    fn setup_signing(&mut self, signing: Option<SigningConfig>);
+
+    /// Set the callback invoked for every skipped frame, or remove it with `None`
+    fn set_error_handler(&mut self, handler: Option<ErrorHandler>);
+
+    /// Number of received frames skipped so far, by reason
+    fn read_error_counts(&self) -> ReadErrorCounts;
+
+    /// Snapshot of the traffic, sequence loss and error statistics of the link
+    fn link_stats(&self) -> LinkStats;
 }
 
 /// Connect to a MAVLink node by address string.
@@ -25,35 +125,668 @@ pub trait MavConnection {
 /// The address must be in one of the following formats:
 ///
 ///  * `tcp:<addr>:<port>`
+///  * `tcpin:<addr>:<port>`
 ///  * `udpin:<addr>:<port>`
 ///  * `udpout:<addr>:<port>`
-///  * `serial:<port>:<baudrate>`
+///  * `udpbcast:<broadcast addr>:<port>`
+///  * `udpmcast:<group addr>:<port>`
+///  * `serial:<port>:<baudrate>[:<framing>][:<flow control>]`, see `SerialConfig`
+///  * `serial:auto`, see `SerialConfig::detect`; the boxed connection does not tell
+///    which port was picked, use `Serial::detect` and `Serial::config` for that
+///  * `unix:<path>`
+///  * `unixgram:<path>[:<remote path>]`
+///  * `pty:[<link path>]`
+///  * `file:<path>`
+///
+/// The address may be followed by query parameters setting the source ids of outgoing
+/// messages, for example `udpout:127.0.0.1:14550?sysid=1&compid=191`; see `parse_address`
+/// for paths that contain `?`. For `udpmcast`,
+/// the `iface` parameter selects the address of the interface to use. A `file` address
+/// replays a tlog as fast as possible, or paced to its recorded timing with a `speed`
+/// factor such as `file:flight.tlog?speed=1`, see `TlogFile`. With `reconnect=1` the link
+/// is re-opened whenever it fails, see `ReconnectingConnection`. The `unix`, `unixgram`
+/// and `pty` connections are only available on unix systems, see `Unix`, `UnixDgram` and
+/// `Pty`.
 ///
 /// The type of the connection is determined at runtime based on the address type, so the
-/// connection is returned as a trait object.
-pub fn connect(address: &str) -> io::Result<Box<MavConnection + Sync + Send>> {
+/// connection is returned as a trait object. Outgoing messages are framed as MAVLink 1 until
+/// `set_protocol_version` is called; incoming messages may use either version.
+pub fn connect(address: &str) -> io::Result<Connection> {
+    ConnectionBuilder::new(address).connect()
+}
+
+fn connect_transport(address: &str, options: &AddressOptions) -> io::Result<Connection> {
+    if address.starts_with("udpmcast:") {
+        let interface = options.interface.unwrap_or(Ipv4Addr::new(0, 0, 0, 0));
+        return Ok(Box::new(try!(Udp::udpmcast(&address["udpmcast:".len()..], interface))));
+    }
+    if options.interface.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The iface parameter is only supported by udpmcast",
+        ));
+    }
+    if address.starts_with("file:") {
+        return Ok(Box::new(try!(TlogFile::open(&address["file:".len()..], options.speed))));
+    }
+    if options.speed.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The speed parameter is only supported by file",
+        ));
+    }
+
     if address.starts_with("tcp:") {
         Ok(Box::new(try!(Tcp::tcp(&address["tcp:".len()..]))))
+    } else if address.starts_with("tcpin:") {
+        Ok(Box::new(try!(TcpServer::tcpin(&address["tcpin:".len()..]))))
     } else if address.starts_with("udpin:") {
         Ok(Box::new(try!(Udp::udpin(&address["udpin:".len()..]))))
     } else if address.starts_with("udpout:") {
         Ok(Box::new(try!(Udp::udpout(&address["udpout:".len()..]))))
+    } else if address.starts_with("udpbcast:") {
+        Ok(Box::new(try!(Udp::udpbcast(&address["udpbcast:".len()..]))))
+    } else if address == "serial:auto" {
+        Ok(Box::new(try!(Serial::detect(DEFAULT_DETECT_TIMEOUT))))
     } else if address.starts_with("serial:") {
         Ok(Box::new(try!(Serial::open(&address["serial:".len()..]))))
+    } else if address.starts_with("unix:") || address.starts_with("unixgram:") || address.starts_with("pty:") {
+        connect_unix(address)
     } else {
         Err(io::Error::new(
             io::ErrorKind::AddrNotAvailable,
-            "Prefix must be one of udpin, udpout, tcp or serial",
+            "Prefix must be one of udpin, udpout, udpbcast, udpmcast, tcp, tcpin, serial, unix, unixgram, pty or file",
         ))
     }
 }
 
+#[cfg(unix)]
+fn connect_unix(address: &str) -> io::Result<Connection> {
+    if address.starts_with("unix:") {
+        Ok(Box::new(try!(Unix::connect(&address["unix:".len()..]))))
+    } else if address.starts_with("unixgram:") {
+        let mut paths = address["unixgram:".len()..].splitn(2, ':');
+        let path = paths.next().unwrap();
+        let remote = paths.next().map(Path::new);
+        Ok(Box::new(try!(UnixDgram::bind(path, remote))))
+    } else {
+        let link = &address["pty:".len()..];
+        let link = if link.is_empty() { None } else { Some(Path::new(link)) };
+        Ok(Box::new(try!(Pty::open(link))))
+    }
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_address: &str) -> io::Result<Connection> {
+    Err(io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        "unix, unixgram and pty connections are only available on unix systems",
+    ))
+}
+
+/// Connection settings given as query parameters after the address
+pub struct AddressOptions {
+    pub system_id: Option<u8>,
+    pub component_id: Option<u8>,
+    pub interface: Option<Ipv4Addr>,
+    pub reconnect: bool,
+    pub speed: Option<f64>,
+}
+
+/// Split the query parameters off an address string.
+///
+/// Recognized parameters are `sysid`, `compid`, `iface`, `reconnect` and `speed`; anything
+/// else is an error. The query starts at the last `?`, so a path containing `?` is given
+/// with a trailing `?`, as in `unix:/tmp/what?.sock?`.
+pub fn parse_address(address: &str) -> io::Result<(&str, AddressOptions)> {
+    let mut options = AddressOptions {
+        system_id: None,
+        component_id: None,
+        interface: None,
+        reconnect: false,
+        speed: None,
+    };
+    let (address, query) = match address.rfind('?') {
+        Some(pos) => (&address[..pos], &address[pos + 1..]),
+        None => return Ok((address, options)),
+    };
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next().unwrap();
+        let value = kv.next().unwrap_or("");
+        match key {
+            "sysid" => options.system_id = Some(try!(parse_id(key, value))),
+            "compid" => options.component_id = Some(try!(parse_id(key, value))),
+            "reconnect" => {
+                options.reconnect = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid reconnect '{}', expected 0 or 1", value),
+                        ))
+                    }
+                }
+            }
+            "iface" => {
+                options.interface = Some(try!(value.parse::<Ipv4Addr>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid iface '{}', expected an IPv4 address", value),
+                    )
+                })))
+            }
+            "speed" => {
+                options.speed = match value.parse::<f64>() {
+                    Ok(speed) if speed > 0.0 => Some(speed),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid speed '{}', expected a positive number", value),
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown address parameter '{}'", key),
+                ))
+            }
+        }
+    }
+    Ok((address, options))
+}
+
+fn parse_id(key: &str, value: &str) -> io::Result<u8> {
+    value.parse::<u8>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} '{}', expected 0-255", key, value),
+        )
+    })
+}
+
+/// Resolve an address to the first socket address it names
+pub fn resolve<T: ToSocketAddrs>(address: T) -> io::Result<SocketAddr> {
+    match try!(address.to_socket_addrs()).next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "Address did not resolve")),
+    }
+}
+
+/// Builder for connections with settings other than the defaults.
+///
+/// ```ignore
+/// let vehicle = ConnectionBuilder::new("udpout:127.0.0.1:14550")
+///     .system_id(1)
+///     .component_id(191)
+///     .protocol_version(MavlinkVersion::V2)
+///     .connect()?;
+/// ```
+///
+/// Settings made on the builder take precedence over query parameters in the address.
+pub struct ConnectionBuilder {
+    address: String,
+    system_id: Option<u8>,
+    component_id: Option<u8>,
+    protocol_version: Option<MavlinkVersion>,
+    signing: Option<SigningConfig>,
+    reconnect: Option<bool>,
+    max_rate: Option<u32>,
+}
+
+impl ConnectionBuilder {
+    /// Start building a connection to an address as accepted by `connect`
+    pub fn new(address: &str) -> ConnectionBuilder {
+        ConnectionBuilder {
+            address: address.to_string(),
+            system_id: None,
+            component_id: None,
+            protocol_version: None,
+            signing: None,
+            reconnect: None,
+            max_rate: None,
+        }
+    }
+
+    /// System id of outgoing messages
+    pub fn system_id(mut self, system_id: u8) -> ConnectionBuilder {
+        self.system_id = Some(system_id);
+        self
+    }
+
+    /// Component id of outgoing messages
+    pub fn component_id(mut self, component_id: u8) -> ConnectionBuilder {
+        self.component_id = Some(component_id);
+        self
+    }
+
+    /// MAVLink version used to frame outgoing messages
+    pub fn protocol_version(mut self, version: MavlinkVersion) -> ConnectionBuilder {
+        self.protocol_version = Some(version);
+        self
+    }
+
+    /// MAVLink 2 message signing configuration
+    pub fn signing(mut self, signing: SigningConfig) -> ConnectionBuilder {
+        self.signing = Some(signing);
+        self
+    }
+
+    /// Re-open the link whenever it fails, see `ReconnectingConnection`.
+    ///
+    /// The link is then first opened when the connection is used, so `connect` does not
+    /// fail if the node is not reachable yet.
+    pub fn reconnect(mut self, reconnect: bool) -> ConnectionBuilder {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Limit outgoing traffic to `bytes_per_sec`, queueing messages by priority, see
+    /// `ThrottledConnection`
+    pub fn max_rate(mut self, bytes_per_sec: u32) -> ConnectionBuilder {
+        self.max_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Open the connection
+    pub fn connect(self) -> io::Result<Connection> {
+        let (address, options) = try!(parse_address(&self.address));
+        let mut conn: Connection = if self.reconnect.unwrap_or(options.reconnect) {
+            let address = address.to_string();
+            let options = AddressOptions {
+                reconnect: false,
+                ..options
+            };
+            Box::new(ReconnectingConnection::new(move || connect_transport(&address, &options)))
+        } else {
+            try!(connect_transport(address, &options))
+        };
+        if let Some(bytes_per_sec) = self.max_rate {
+            conn = Box::new(ThrottledConnection::new(conn, bytes_per_sec));
+        }
+        let (default_system_id, default_component_id) = conn.get_source_ids();
+        conn.set_source_ids(
+            self.system_id.or(options.system_id).unwrap_or(default_system_id),
+            self.component_id.or(options.component_id).unwrap_or(default_component_id),
+        );
+        if let Some(version) = self.protocol_version {
+            conn.set_protocol_version(version);
+        }
+        if self.signing.is_some() {
+            conn.setup_signing(self.signing);
+        }
+        Ok(conn)
+    }
+}
+
+/// Shortest read timeout, as sockets and ports do not accept a zero timeout
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// Read timeout that expires at `deadline`, but lets a read be attempted if it has passed
+pub fn read_timeout(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now + MIN_READ_TIMEOUT {
+        deadline - now
+    } else {
+        MIN_READ_TIMEOUT
+    }
+}
+
+pub fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |deadline| Instant::now() >= deadline)
+}
+
+/// Whether a read of `socket` should be attempted before `deadline`.
+///
+/// Once the deadline has passed, a read is only attempted if data is already waiting,
+/// since read timeouts cannot be shorter than `MIN_READ_TIMEOUT`.
+#[cfg(unix)]
+fn ready_to_read<T: AsRawFd>(socket: &T, deadline: Option<Instant>) -> io::Result<bool> {
+    if deadline_passed(deadline) {
+        wait_readable(socket.as_raw_fd(), deadline)
+    } else {
+        Ok(true)
+    }
+}
+
+#[cfg(not(unix))]
+fn ready_to_read<T>(_socket: &T, _deadline: Option<Instant>) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Whether a read failed because its timeout expired (`WouldBlock` on unix, `TimedOut` on windows)
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Counts skipped frames and forwards them to the error handler
+pub struct ErrorReporter {
+    counts: Mutex<ReadErrorCounts>,
+    handler: Option<ErrorHandler>,
+}
+
+impl ErrorReporter {
+    pub fn new() -> ErrorReporter {
+        ErrorReporter {
+            counts: Mutex::new(ReadErrorCounts::default()),
+            handler: None,
+        }
+    }
+
+    pub fn report(&self, e: &MessageReadError) {
+        self.counts.lock().unwrap().record(e);
+        if let Some(ref handler) = self.handler {
+            handler(e);
+        }
+    }
+
+    pub fn counts(&self) -> ReadErrorCounts {
+        *self.counts.lock().unwrap()
+    }
+
+    pub fn set_handler(&mut self, handler: Option<ErrorHandler>) {
+        self.handler = handler;
+    }
+}
+
+/// Identity, protocol version, signing and statistics of a link, shared by every
+/// transport. `link_config_methods!` implements the `MavConnection` methods that only
+/// touch it, for connections keeping it in a `link` field.
+pub struct LinkConfig {
+    pub protocol_version: MavlinkVersion,
+    pub system_id: u8,
+    pub component_id: u8,
+    pub signing: Option<SigningData>,
+    pub errors: ErrorReporter,
+    pub stats: StatsTracker,
+}
+
+impl LinkConfig {
+    pub fn new() -> LinkConfig {
+        LinkConfig {
+            protocol_version: MavlinkVersion::V1,
+            system_id: DEFAULT_SYSTEM_ID,
+            component_id: DEFAULT_COMPONENT_ID,
+            signing: None,
+            errors: ErrorReporter::new(),
+            stats: StatsTracker::new(),
+        }
+    }
+
+    /// Header of the next message sent, advancing the link's `sequence`
+    pub fn next_header(&self, sequence: &mut u8) -> Header {
+        let header = Header {
+            sequence: *sequence,
+            system_id: self.system_id,
+            component_id: self.component_id,
+        };
+        *sequence = sequence.wrapping_add(1);
+        header
+    }
+}
+
+macro_rules! link_config_methods {
+    () => {
+        fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+            self.link.system_id = system_id;
+            self.link.component_id = component_id;
+        }
+
+        fn get_source_ids(&self) -> (u8, u8) {
+            (self.link.system_id, self.link.component_id)
+        }
+
+        fn set_protocol_version(&mut self, version: $crate::MavlinkVersion) {
+            self.link.protocol_version = version;
+        }
+
+        fn get_protocol_version(&self) -> $crate::MavlinkVersion {
+            self.link.protocol_version
+        }
+
+        fn setup_signing(&mut self, signing: Option<$crate::SigningConfig>) {
+            self.link.signing = signing.map($crate::SigningData::from_config);
+        }
+
+        fn set_error_handler(&mut self, handler: Option<$crate::ErrorHandler>) {
+            self.link.errors.set_handler(handler);
+        }
+
+        fn read_error_counts(&self) -> $crate::ReadErrorCounts {
+            self.link.errors.counts()
+        }
+
+        fn link_stats(&self) -> $crate::LinkStats {
+            self.link.stats.snapshot(self.link.errors.counts())
+        }
+    };
+}
+
+/// Size of the chunks read from stream connections
+const STREAM_READ_CHUNK: usize = 4096;
+
+/// Buffered framing for byte stream connections.
+///
+/// Bytes are read in large chunks and framed by a `FrameDecoder`, so a partially
+/// received frame survives read timeouts and high rate streams need few syscalls.
+struct StreamDecoder {
+    decoder: FrameDecoder,
+    buf: Vec<u8>,
+    /// Time of the last read, which completed any frame decoded since
+    last_read: ReceiveTime,
+}
+
+impl StreamDecoder {
+    fn new() -> StreamDecoder {
+        StreamDecoder {
+            decoder: FrameDecoder::new(),
+            buf: vec![0; STREAM_READ_CHUNK],
+            last_read: ReceiveTime::now(),
+        }
+    }
+
+    /// Take the next frame from the buffered bytes, reporting skipped frames
+    fn next_frame(
+        &mut self,
+        signing: Option<&SigningData>,
+        errors: &ErrorReporter,
+        stats: &StatsTracker,
+    ) -> Option<MavFrame> {
+        while let Some(result) = self.decoder.next_frame(signing) {
+            match result {
+                Ok(mut frame) => {
+                    frame.received = Some(self.last_read);
+                    stats.record_received(&frame.header);
+                    return Some(frame);
+                }
+                Err(e) => errors.report(&e),
+            }
+        }
+        None
+    }
+
+    /// Read one chunk of bytes from the stream into the decoder
+    fn fill<R: Read>(&mut self, r: &mut R, stats: &StatsTracker) -> io::Result<()> {
+        let n = try!(r.read(&mut self.buf));
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+        self.push(&buf[..n], ReceiveTime::now(), stats);
+        self.buf = buf;
+        Ok(())
+    }
+
+    /// Add a chunk of bytes read elsewhere at time `received`
+    fn push(&mut self, data: &[u8], received: ReceiveTime, stats: &StatsTracker) {
+        self.last_read = received;
+        stats.record_bytes_received(data.len());
+        self.decoder.push(data);
+    }
+}
+
+/// Peers of a UDP server that send nothing for this long are forgotten
+const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Datagrams sent to a multicast group that are remembered to recognize them when the
+/// group loops them back to us
+const MULTICAST_SENT_MEMORY: usize = 32;
+
+/// A remote node that sent datagrams to a UDP server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpPeer {
+    pub addr: SocketAddr,
+    /// Systems whose frames were last received from this peer
+    pub system_ids: Vec<u8>,
+    pub last_seen: Instant,
+}
+
+/// Peers of a UDP server, with the systems reachable through each of them
+pub struct PeerTable {
+    pub peers: Vec<UdpPeer>,
+    pub timeout: Duration,
+}
+
+impl PeerTable {
+    fn new() -> PeerTable {
+        PeerTable {
+            peers: Vec::new(),
+            timeout: DEFAULT_PEER_TIMEOUT,
+        }
+    }
+
+    /// Note a datagram from `addr`, adding it as a new peer if needed
+    fn seen(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(peer) = self.peers.iter_mut().find(|peer| peer.addr == addr) {
+            peer.last_seen = now;
+            return;
+        }
+        self.peers.push(UdpPeer {
+            addr: addr,
+            system_ids: Vec::new(),
+            last_seen: now,
+        });
+    }
+
+    /// Note that frames of `system_id` come from `addr`, and no longer from any other peer
+    pub fn learn(&mut self, addr: SocketAddr, system_id: u8) {
+        for peer in self.peers.iter_mut() {
+            if peer.addr == addr {
+                if !peer.system_ids.contains(&system_id) {
+                    peer.system_ids.push(system_id);
+                }
+            } else {
+                peer.system_ids.retain(|&id| id != system_id);
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.peers.retain(|peer| now.duration_since(peer.last_seen) < timeout);
+    }
+
+    /// Peers a message should go to: the owner of its target system if known, otherwise all
+    fn destinations(&self, target_system: Option<u8>) -> Vec<SocketAddr> {
+        if let Some(target) = target_system {
+            // target system 0 is a broadcast
+            if target != 0 {
+                if let Some(peer) = self.peers.iter().find(|peer| peer.system_ids.contains(&target)) {
+                    return vec![peer.addr];
+                }
+            }
+        }
+        self.peers.iter().map(|peer| peer.addr).collect()
+    }
+}
+
 struct UdpWrite {
     socket: UdpSocket,
-    dest: Option<SocketAddr>,
     sequence: u8,
 }
 
+/// Where sent datagrams go, as learned from received ones.
+///
+/// Kept apart from `UdpWrite` so receiving never waits for a send in progress.
+pub struct UdpRoute {
+    pub mode: UdpMode,
+    dest: Option<SocketAddr>,
+    /// Address datagrams are sent to until a node is known
+    broadcast: Option<SocketAddr>,
+    /// Whether a broadcast socket has locked onto the first node that answered
+    locked: bool,
+    /// When the node a broadcast socket locked onto was last heard from
+    last_answer: Instant,
+    pub peers: PeerTable,
+    /// Datagrams recently sent to a multicast group, oldest first
+    sent: VecDeque<Vec<u8>>,
+}
+
+impl UdpRoute {
+    pub fn new(mode: UdpMode, dest: Option<SocketAddr>) -> UdpRoute {
+        UdpRoute {
+            mode: mode,
+            dest: dest,
+            broadcast: dest,
+            locked: false,
+            last_answer: Instant::now(),
+            peers: PeerTable::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Note a datagram received from `src`, returning false if it is one of ours looped
+    /// back by a multicast group
+    pub fn received(&mut self, src: SocketAddr, datagram: &[u8], now: Instant) -> bool {
+        match self.mode {
+            UdpMode::Server => self.peers.seen(src, now),
+            UdpMode::Broadcast => {
+                if !self.locked || self.dest == Some(src) {
+                    self.dest = Some(src);
+                    self.locked = true;
+                    self.last_answer = now;
+                }
+            }
+            UdpMode::Multicast => {
+                if let Some(i) = self.sent.iter().position(|sent| &sent[..] == datagram) {
+                    self.sent.remove(i);
+                    return false;
+                }
+            }
+            UdpMode::Client => (),
+        }
+        true
+    }
+
+    /// Addresses a message for `target_system` is sent to
+    pub fn destinations(&mut self, target_system: Option<u8>) -> Vec<SocketAddr> {
+        if self.mode == UdpMode::Server {
+            self.peers.expire(Instant::now());
+            self.peers.destinations(target_system)
+        } else if self.locked && self.last_answer.elapsed() >= self.peers.timeout {
+            // the node we talked to went away, look for another one
+            self.locked = false;
+            self.dest = self.broadcast;
+            self.dest.into_iter().collect()
+        } else {
+            self.dest.into_iter().collect()
+        }
+    }
+
+    /// Note a datagram about to be sent, so a multicast group looping it back is recognized
+    pub fn sending(&mut self, datagram: &[u8]) {
+        if self.mode == UdpMode::Multicast {
+            if self.sent.len() == MULTICAST_SENT_MEMORY {
+                self.sent.pop_front();
+            }
+            self.sent.push_back(datagram.to_vec());
+        }
+    }
+}
+
 struct PacketBuf {
     buf: Vec<u8>,
     start: usize,
@@ -101,95 +834,233 @@ impl Read for PacketBuf {
 struct UdpRead {
     socket: UdpSocket,
     recv_buf: PacketBuf,
+    /// Arrival time of the datagram in `recv_buf`
+    received: ReceiveTime,
+    /// Sender of the datagram in `recv_buf`
+    source: Option<SocketAddr>,
+}
+
+/// Role of a UDP socket
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdpMode {
+    /// Send to a fixed destination
+    Client,
+    /// Send to the peers that sent us datagrams
+    Server,
+    /// Send to a broadcast address until the first datagram arrives, then to its sender
+    Broadcast,
+    /// Send to a multicast group that we also receive from
+    Multicast,
 }
 
-/// UDP MAVLink connection
+/// UDP MAVLink connection.
+///
+/// In server (`udpin`) mode every node that sends a datagram becomes a peer until it
+/// stays silent for the peer timeout. Messages with a target system go to the peer that
+/// system was last heard from; all other messages go to every peer.
 pub struct Udp {
     read: Mutex<UdpRead>,
     write: Mutex<UdpWrite>,
-    server: bool,
+    route: Mutex<UdpRoute>,
+    link: LinkConfig,
 }
 
 impl Udp {
-    fn new(socket: UdpSocket, server: bool, dest: Option<SocketAddr>) -> io::Result<Udp> {
+    fn new(socket: UdpSocket, mode: UdpMode, dest: Option<SocketAddr>) -> io::Result<Udp> {
         Ok(Udp {
-            server: server,
+            link: LinkConfig::new(),
             read: Mutex::new(UdpRead {
                 socket: try!(socket.try_clone()),
                 recv_buf: PacketBuf::new(),
+                received: ReceiveTime::now(),
+                source: None,
             }),
             write: Mutex::new(UdpWrite {
                 socket: socket,
-                dest: dest,
                 sequence: 0,
             }),
+            route: Mutex::new(UdpRoute::new(mode, dest)),
         })
     }
 
     pub fn udpin<T: ToSocketAddrs>(address: T) -> io::Result<Udp> {
         let addr = address.to_socket_addrs().unwrap().next().unwrap();
         let socket = try!(UdpSocket::bind(&addr));
-        Udp::new(socket, true, None)
+        Udp::new(socket, UdpMode::Server, None)
     }
 
     pub fn udpout<T: ToSocketAddrs>(address: T) -> io::Result<Udp> {
         let addr = address.to_socket_addrs().unwrap().next().unwrap();
         let socket = try!(UdpSocket::bind(&SocketAddr::from_str("0.0.0.0:0").unwrap()));
-        Udp::new(socket, false, Some(addr))
+        Udp::new(socket, UdpMode::Client, Some(addr))
     }
-}
 
-impl MavConnection for Udp {
-    fn recv(&self) -> io::Result<MavMessage> {
+    /// Send to a broadcast address until a node answers, then talk only to that node.
+    ///
+    /// If that node stays silent for the peer timeout, messages are broadcast again
+    /// until the next node answers.
+    pub fn udpbcast<T: ToSocketAddrs>(address: T) -> io::Result<Udp> {
+        let addr = try!(resolve(address));
+        let socket = try!(UdpSocket::bind(&SocketAddr::from_str("0.0.0.0:0").unwrap()));
+        try!(socket.set_broadcast(true));
+        Udp::new(socket, UdpMode::Broadcast, Some(addr))
+    }
+
+    /// Join an IPv4 multicast group, sending to and receiving from the group.
+    ///
+    /// `interface` is the address of the network interface to use, or `0.0.0.0` to let
+    /// the system choose. The port is shared with other sockets on the same host, which
+    /// receive our frames through multicast loopback; the frames looped back to this
+    /// socket itself are dropped.
+    pub fn udpmcast<T: ToSocketAddrs>(address: T, interface: Ipv4Addr) -> io::Result<Udp> {
+        let addr = try!(resolve(address));
+        let group = match addr {
+            SocketAddr::V4(ref addr) if addr.ip().is_multicast() => *addr.ip(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Expected an IPv4 multicast group address",
+                ))
+            }
+        };
+        let builder = try!(UdpBuilder::new_v4());
+        try!(builder.reuse_address(true));
+        let socket = try!(builder.bind((Ipv4Addr::new(0, 0, 0, 0), addr.port())));
+        try!(socket.join_multicast_v4(&group, &interface));
+        if !interface.is_unspecified() {
+            try!(socket.set_multicast_if_v4(&interface));
+        }
+        Udp::new(socket, UdpMode::Multicast, Some(addr))
+    }
+
+    /// Peers currently known to a server, empty in client mode
+    pub fn peers(&self) -> Vec<UdpPeer> {
+        let mut route = self.route.lock().unwrap();
+        route.peers.expire(Instant::now());
+        route.peers.peers.clone()
+    }
+
+    /// Set how long a server keeps a peer that sends nothing, and how long a broadcast
+    /// socket keeps talking to a node that sends nothing
+    pub fn set_peer_timeout(&mut self, timeout: Duration) {
+        self.route.lock().unwrap().peers.timeout = timeout;
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
         let mut guard = self.read.lock().unwrap();
         let state = &mut *guard;
+        let mut attempted = false;
         loop {
             if state.recv_buf.len() == 0 {
-                let (len, src) = try!(state.socket.recv_from(state.recv_buf.reset()));
+                if attempted && deadline_passed(deadline) {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                attempted = true;
+                if !try!(ready_to_read(&state.socket, deadline)) {
+                    continue;
+                }
+                try!(state.socket.set_read_timeout(deadline.map(read_timeout)));
+                let (len, src) = match state.socket.recv_from(state.recv_buf.reset()) {
+                    Ok(received) => received,
+                    Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
                 state.recv_buf.set_len(len);
+                state.received = ReceiveTime::now();
 
-                if self.server {
-                    self.write.lock().unwrap().dest = Some(src);
+                {
+                    let mut route = self.route.lock().unwrap();
+                    if !route.received(src, state.recv_buf.slice(), state.received.monotonic) {
+                        state.recv_buf.set_len(0);
+                        continue;
+                    }
+                    if route.mode == UdpMode::Server {
+                        state.source = Some(src);
+                    }
                 }
+                self.link.stats.record_bytes_received(len);
             }
 
-            if let Ok((_, m)) = read(&mut state.recv_buf) {
-                return Ok(m);
+            match read_frame(&mut state.recv_buf, self.link.signing.as_ref()) {
+                Ok(mut frame) => {
+                    if let Some(src) = state.source {
+                        self.route.lock().unwrap().peers.learn(src, frame.header.system_id);
+                    }
+                    frame.received = Some(state.received);
+                    self.link.stats.record_received(&frame.header);
+                    return Ok(frame);
+                }
+                // the rest of the datagram did not hold a complete frame
+                Err(MessageReadError::Eof) => (),
+                Err(MessageReadError::Io(e)) => return Err(e.into()),
+                Err(e) => self.link.errors.report(&e),
             }
         }
     }
 
-    fn send(&self, data: &MavMessage) -> io::Result<()> {
-        let mut guard = self.write.lock().unwrap();
-        let state = &mut *guard;
-
-        let header = Header {
-            sequence: state.sequence,
-            system_id: 255,
-            component_id: 0,
-        };
-
-        state.sequence = state.sequence.wrapping_add(1);
+    fn write_locked(
+        &self,
+        state: &mut UdpWrite,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let destinations = self.route.lock().unwrap().destinations(data.target_system());
+        if destinations.is_empty() {
+            return Ok(());
+        }
 
-        if let Some(addr) = state.dest {
-            let mut buf = Vec::new();
-            try!(write(&mut buf, header, data));
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        self.route.lock().unwrap().sending(&buf);
+        for addr in destinations.iter() {
             try!(state.socket.send_to(&buf, addr));
         }
-
+        self.link.stats.record_sent(buf.len() * destinations.len());
         Ok(())
     }
 }
 
+impl MavConnection for Udp {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut state = self.write.lock().unwrap();
+
+        let header = self.link.next_header(&mut state.sequence);
+        self.write_locked(&mut state, self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut state = self.write.lock().unwrap();
+        self.write_locked(&mut state, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
 /// TCP MAVLink connection
 pub struct Tcp {
-    read: Mutex<TcpStream>,
+    read: Mutex<TcpRead>,
     write: Mutex<TcpWrite>,
+    link: LinkConfig,
 }
 
-struct TcpWrite {
+struct TcpRead {
     socket: TcpStream,
-    sequence: u8,
+    decoder: StreamDecoder,
+}
+
+struct TcpWrite {
+    socket: TcpStream,
+    sequence: u8,
 }
 
 impl Tcp {
@@ -197,93 +1068,1357 @@ impl Tcp {
         let addr = address.to_socket_addrs().unwrap().next().unwrap();
         let socket = try!(TcpStream::connect(&addr));
         Ok(Tcp {
-            read: Mutex::new(try!(socket.try_clone())),
+            read: Mutex::new(TcpRead {
+                socket: try!(socket.try_clone()),
+                decoder: StreamDecoder::new(),
+            }),
             write: Mutex::new(TcpWrite {
                 socket: socket,
                 sequence: 0,
             }),
+            link: LinkConfig::new(),
         })
     }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        let mut attempted = false;
+        loop {
+            if let Some(frame) = state.decoder.next_frame(self.link.signing.as_ref(), &self.link.errors, &self.link.stats) {
+                return Ok(frame);
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+            if !try!(ready_to_read(&state.socket, deadline)) {
+                continue;
+            }
+            try!(state.socket.set_read_timeout(deadline.map(read_timeout)));
+            match state.decoder.fill(&mut state.socket, &self.link.stats) {
+                Ok(()) => (),
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_locked(
+        &self,
+        lock: &mut TcpWrite,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        try!(lock.socket.write_all(&buf));
+        self.link.stats.record_sent(buf.len());
+        Ok(())
+    }
 }
 
 impl MavConnection for Tcp {
-    fn recv(&self) -> io::Result<MavMessage> {
-        let mut lock = self.read.lock().unwrap();
-        read(&mut *lock).map(|(_, pkt)| pkt)
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
     }
 
     fn send(&self, data: &MavMessage) -> io::Result<()> {
         let mut lock = self.write.lock().unwrap();
 
-        let header = Header {
-            sequence: lock.sequence,
-            system_id: 255,
-            component_id: 0,
-        };
+        let header = self.link.next_header(&mut lock.sequence);
+        self.write_locked(&mut lock, self.link.protocol_version, header, data)
+    }
 
-        lock.sequence = lock.sequence.wrapping_add(1);
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        self.write_locked(&mut lock, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+/// Serial port and line settings, as given in a `serial:` address.
+///
+/// The settings have the form `<port>:<baud>[:<framing>][:<flow control>]`, where the
+/// framing gives the data bits, parity (`N`, `E` or `O`) and stop bits, such as `8N1` or
+/// `7E2`, and the flow control is `none`, `rtscts` or `xonxoff`. Without them the port is
+/// configured as 8N1 with no flow control, for example `/dev/ttyUSB0:57600:8N1:rtscts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub port: String,
+    pub baud: usize,
+    pub char_size: ::serial::CharSize,
+    pub parity: ::serial::Parity,
+    pub stop_bits: ::serial::StopBits,
+    pub flow_control: ::serial::FlowControl,
+}
+
+impl SerialConfig {
+    /// 8N1 settings with no flow control
+    pub fn new(port: &str, baud: usize) -> SerialConfig {
+        SerialConfig {
+            port: port.to_string(),
+            baud: baud,
+            char_size: ::serial::Bits8,
+            parity: ::serial::ParityNone,
+            stop_bits: ::serial::Stop1,
+            flow_control: ::serial::FlowNone,
+        }
+    }
 
-        try!(write(&mut lock.socket, header, data));
+    pub fn parse(settings: &str) -> io::Result<SerialConfig> {
+        let mut fields = settings.split(':');
+        let port = fields.next().unwrap();
+        if port.is_empty() {
+            return Err(invalid_serial(settings, "missing port"));
+        }
+        let baud = match fields.next().map(|baud| (baud, baud.parse::<usize>())) {
+            Some((_, Ok(baud))) if baud > 0 => baud,
+            Some((baud, _)) => return Err(invalid_serial(settings, &format!("invalid baud rate '{}'", baud))),
+            None => return Err(invalid_serial(settings, "missing baud rate")),
+        };
+        let mut config = SerialConfig::new(port, baud);
+        let mut framing = false;
+        let mut flow = false;
+        for field in fields {
+            let is_framing = field.chars().next().map_or(false, |c| c.is_digit(10));
+            if (is_framing && framing) || (!is_framing && flow) {
+                return Err(invalid_serial(settings, &format!("unexpected setting '{}'", field)));
+            }
+            if is_framing {
+                try!(config.parse_framing(field).map_err(|e| invalid_serial(settings, &e)));
+                framing = true;
+            } else {
+                config.flow_control = match field.to_lowercase().as_str() {
+                    "none" => ::serial::FlowNone,
+                    "rtscts" => ::serial::FlowHardware,
+                    "xonxoff" => ::serial::FlowSoftware,
+                    _ => {
+                        return Err(invalid_serial(
+                            settings,
+                            &format!("invalid flow control '{}', expected none, rtscts or xonxoff", field),
+                        ))
+                    }
+                };
+                flow = true;
+            }
+        }
+        Ok(config)
+    }
 
+    /// Parse framing such as `8N1`
+    fn parse_framing(&mut self, framing: &str) -> Result<(), String> {
+        let chars: Vec<char> = framing.chars().collect();
+        if chars.len() != 3 {
+            return Err(format!("invalid framing '{}', expected data bits, parity and stop bits such as 8N1", framing));
+        }
+        self.char_size = match chars[0] {
+            '5' => ::serial::Bits5,
+            '6' => ::serial::Bits6,
+            '7' => ::serial::Bits7,
+            '8' => ::serial::Bits8,
+            c => return Err(format!("invalid data bits '{}', expected 5 to 8", c)),
+        };
+        self.parity = match chars[1].to_ascii_uppercase() {
+            'N' => ::serial::ParityNone,
+            'E' => ::serial::ParityEven,
+            'O' => ::serial::ParityOdd,
+            c => return Err(format!("invalid parity '{}', expected N, E or O", c)),
+        };
+        self.stop_bits = match chars[2] {
+            '1' => ::serial::Stop1,
+            '2' => ::serial::Stop2,
+            c => return Err(format!("invalid stop bits '{}', expected 1 or 2", c)),
+        };
         Ok(())
     }
+
+    fn port_settings(&self) -> ::serial::PortSettings {
+        ::serial::PortSettings {
+            baud_rate: ::serial::BaudRate::from_speed(self.baud),
+            char_size: self.char_size,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            flow_control: self.flow_control,
+        }
+    }
+}
+
+impl fmt::Display for SerialConfig {
+    /// Formats the settings as accepted by `parse`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let char_size = match self.char_size {
+            ::serial::Bits5 => 5,
+            ::serial::Bits6 => 6,
+            ::serial::Bits7 => 7,
+            ::serial::Bits8 => 8,
+        };
+        let parity = match self.parity {
+            ::serial::ParityNone => 'N',
+            ::serial::ParityEven => 'E',
+            ::serial::ParityOdd => 'O',
+        };
+        let stop_bits = match self.stop_bits {
+            ::serial::Stop1 => 1,
+            ::serial::Stop2 => 2,
+        };
+        let flow_control = match self.flow_control {
+            ::serial::FlowNone => "none",
+            ::serial::FlowHardware => "rtscts",
+            ::serial::FlowSoftware => "xonxoff",
+        };
+        write!(f, "{}:{}:{}{}{}:{}", self.port, self.baud, char_size, parity, stop_bits, flow_control)
+    }
+}
+
+/// Baud rates tried by serial auto-detection, most common first
+pub const DETECT_BAUD_RATES: [usize; 6] = [57600, 115200, 921600, 460800, 230400, 38400];
+
+/// Time to wait for a HEARTBEAT at each device and baud rate; heartbeats are sent at 1Hz
+pub const DEFAULT_DETECT_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Serial devices that may be a flight controller or telemetry radio, in name order
+pub fn serial_candidates() -> Vec<String> {
+    let mut ports = Vec::new();
+    if let Ok(entries) = ::std::fs::read_dir("/dev") {
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let candidate = ["ttyACM", "ttyUSB", "tty.usbmodem", "tty.usbserial"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix));
+            if candidate {
+                ports.push(format!("/dev/{}", name));
+            }
+        }
+    }
+    ports.sort();
+    ports
+}
+
+impl SerialConfig {
+    /// Find the first candidate device and baud rate that produce a HEARTBEAT.
+    ///
+    /// Tries every device from `serial_candidates` at every rate of `DETECT_BAUD_RATES`,
+    /// waiting up to `timeout` at each, and returns the settings that worked.
+    pub fn detect(timeout: Duration) -> io::Result<SerialConfig> {
+        let ports = serial_candidates();
+        if ports.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No serial devices found for auto-detection (looked for /dev/ttyACM*, /dev/ttyUSB*, \
+                 /dev/tty.usbmodem* and /dev/tty.usbserial*)",
+            ));
+        }
+        SerialConfig::detect_ports(&ports, &DETECT_BAUD_RATES, timeout)
+    }
+
+    /// Find the first of `ports` and `bauds` that produce a HEARTBEAT within `timeout`
+    pub fn detect_ports<S: AsRef<str>>(ports: &[S], bauds: &[usize], timeout: Duration) -> io::Result<SerialConfig> {
+        for port in ports {
+            for &baud in bauds {
+                let config = SerialConfig::new(port.as_ref(), baud);
+                let port = match open_port(&config) {
+                    Ok(port) => port,
+                    // busy or not a serial port; the other rates will not fare better
+                    Err(_) => break,
+                };
+                let conn = match Serial::from_port(port, &config) {
+                    Ok(conn) => conn,
+                    // this rate is not supported, but another one may be
+                    Err(_) => continue,
+                };
+                if conn.wait_heartbeat(timeout) {
+                    return Ok(config);
+                }
+            }
+        }
+        let ports: Vec<&str> = ports.iter().map(AsRef::as_ref).collect();
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No MAVLink HEARTBEAT received on {} at any baud rate", ports.join(", ")),
+        ))
+    }
+}
+
+fn open_port(config: &SerialConfig) -> io::Result<::serial::SystemPort> {
+    ::serial::open(&config.port).map_err(|e| {
+        let e = io::Error::from(e);
+        io::Error::new(e.kind(), format!("Could not open serial port {}: {}", config.port, e))
+    })
+}
+
+fn invalid_serial(settings: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid serial settings '{}': {}", settings, reason),
+    )
 }
 
 /// Serial MAVLINK connection
 pub struct Serial {
     port: Mutex<::serial::SystemPort>,
+    /// Duplicate of the port's file descriptor that sends are written to, so they need
+    /// not wait for a read in progress
+    #[cfg(unix)]
+    writer: Mutex<SerialWriter>,
+    read: Mutex<StreamDecoder>,
     sequence: Mutex<u8>,
+    link: LinkConfig,
+    config: SerialConfig,
 }
 
 impl Serial {
+    /// Open a serial port with settings as described by `SerialConfig`
     pub fn open(settings: &str) -> io::Result<Serial> {
-        let settings: Vec<&str> = settings.split(":").collect();
-        let port = settings[0];
-        let baud = settings[1].parse::<usize>().unwrap();
-        let mut port = ::serial::open(port)?;
-
-        let baud = ::serial::core::BaudRate::from_speed(baud);
-        let settings = ::serial::core::PortSettings {
-            baud_rate: baud,
-            char_size: ::serial::Bits8,
-            parity: ::serial::ParityNone,
-            stop_bits: ::serial::Stop1,
-            flow_control: ::serial::FlowNone,
-        };
+        Serial::open_config(&try!(SerialConfig::parse(settings)))
+    }
+
+    /// Open the port found by `SerialConfig::detect`, see `config` for the port and
+    /// baud rate that were picked
+    pub fn detect(timeout: Duration) -> io::Result<Serial> {
+        Serial::open_config(&try!(SerialConfig::detect(timeout)))
+    }
+
+    pub fn open_config(config: &SerialConfig) -> io::Result<Serial> {
+        Serial::from_port(try!(open_port(config)), config)
+    }
 
-        port.configure(&settings)?;
+    /// Configure an open port as described by `config`
+    fn from_port(mut port: ::serial::SystemPort, config: &SerialConfig) -> io::Result<Serial> {
+        try!(port.configure(&config.port_settings()).map_err(|e| {
+            let e = io::Error::from(e);
+            let reason = if e.kind() == io::ErrorKind::InvalidInput {
+                format!("baud rate {} or the framing is not supported by this port", config.baud)
+            } else {
+                e.to_string()
+            };
+            io::Error::new(e.kind(), format!("Could not configure serial port {}: {}", config.port, reason))
+        }));
 
         Ok(Serial {
+            #[cfg(unix)]
+            writer: Mutex::new(SerialWriter {
+                file: try!(duplicate_fd(port.as_raw_fd())),
+                timeout: port.timeout(),
+            }),
             port: Mutex::new(port),
+            read: Mutex::new(StreamDecoder::new()),
             sequence: Mutex::new(0),
+            link: LinkConfig::new(),
+            config: config.clone(),
         })
     }
+
+    /// Settings the port was opened with
+    pub fn config(&self) -> &SerialConfig {
+        &self.config
+    }
+
+    /// Whether a HEARTBEAT with a valid CRC arrives within `timeout`
+    fn wait_heartbeat(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.recv_until(Some(deadline)) {
+                Ok(frame) => {
+                    if let MavMessage::HEARTBEAT(_) = frame.msg {
+                        return true;
+                    }
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut decoder = self.read.lock().unwrap();
+        let mut attempted = false;
+
+        loop {
+            if let Some(frame) = decoder.next_frame(self.link.signing.as_ref(), &self.link.errors, &self.link.stats) {
+                return Ok(frame);
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+
+            // sends have their own handle on unix; elsewhere they go out between reads
+            let mut port = self.port.lock().unwrap();
+            if !try!(ready_to_read(&*port, deadline)) {
+                continue;
+            }
+            let port_timeout = port.timeout();
+            if let Some(deadline) = deadline {
+                try!(port.set_timeout(cmp::min(port_timeout, read_timeout(deadline))).map_err(io::Error::from));
+            }
+            let result = decoder.fill(&mut *port, &self.link.stats);
+            if deadline.is_some() {
+                try!(port.set_timeout(port_timeout).map_err(io::Error::from));
+            }
+            match result {
+                Ok(()) => (),
+                // the read timeout expired; any partial frame stays in the decoder
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn writer(&self) -> MutexGuard<SerialWriter> {
+        self.writer.lock().unwrap()
+    }
+
+    #[cfg(not(unix))]
+    fn writer(&self) -> MutexGuard<::serial::SystemPort> {
+        self.port.lock().unwrap()
+    }
+
+    fn write_locked(
+        &self,
+        writer: &mut Write,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        try!(writer.write_all(&buf));
+        self.link.stats.record_sent(buf.len());
+        Ok(())
+    }
 }
 
 impl MavConnection for Serial {
-    fn recv(&self) -> io::Result<MavMessage> {
-        let mut port = self.port.lock().unwrap();
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
 
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut writer = self.writer();
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let header = self.link.next_header(&mut *sequence);
+        self.write_locked(&mut *writer, self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut writer = self.writer();
+        self.write_locked(&mut *writer, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+/// Open a second handle on a file descriptor
+#[cfg(unix)]
+fn duplicate_fd(fd: RawFd) -> io::Result<File> {
+    let fd = unsafe { libc::dup(fd) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Write end of a serial port, which gives up like the port itself when the other side
+/// does not take data, for example while hardware flow control holds CTS deasserted
+#[cfg(unix)]
+struct SerialWriter {
+    file: File,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl Write for SerialWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !try!(wait_fd(self.file.as_raw_fd(), libc::POLLOUT, Some(Instant::now() + self.timeout))) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "serial port did not accept data"));
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Wait until `fd` can be read, giving up at `deadline` if there is one.
+///
+/// Returns `false` if the deadline passed or the wait was interrupted.
+#[cfg(unix)]
+fn wait_readable(fd: RawFd, deadline: Option<Instant>) -> io::Result<bool> {
+    wait_fd(fd, libc::POLLIN, deadline)
+}
+
+/// Wait until one of `events` occurs on `fd`, giving up at `deadline` if there is one
+#[cfg(unix)]
+fn wait_fd(fd: RawFd, events: libc::c_short, deadline: Option<Instant>) -> io::Result<bool> {
+    let timeout_ms = match deadline {
+        // a deadline that has passed only checks for waiting data
+        Some(deadline) if deadline_passed(Some(deadline)) => 0,
+        Some(deadline) => {
+            let timeout = read_timeout(deadline);
+            // round up, so the deadline has passed when poll times out
+            (timeout.as_secs() * 1000 + (timeout.subsec_nanos() as u64 + 999_999) / 1_000_000) as libc::c_int
+        }
+        None => -1,
+    };
+    let mut pollfd = libc::pollfd {
+        fd: fd,
+        events: events,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Unix domain stream socket MAVLink connection
+#[cfg(unix)]
+pub struct Unix {
+    read: Mutex<UnixRead>,
+    write: Mutex<UnixWrite>,
+    link: LinkConfig,
+}
+
+#[cfg(unix)]
+struct UnixRead {
+    socket: UnixStream,
+    decoder: StreamDecoder,
+}
+
+#[cfg(unix)]
+struct UnixWrite {
+    socket: UnixStream,
+    sequence: u8,
+}
+
+#[cfg(unix)]
+impl Unix {
+    /// Connect to a unix domain stream socket listening at `path`
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Unix> {
+        let socket = try!(UnixStream::connect(path));
+        Ok(Unix {
+            read: Mutex::new(UnixRead {
+                socket: try!(socket.try_clone()),
+                decoder: StreamDecoder::new(),
+            }),
+            write: Mutex::new(UnixWrite {
+                socket: socket,
+                sequence: 0,
+            }),
+            link: LinkConfig::new(),
+        })
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        let mut attempted = false;
         loop {
-            if let Ok((_, m)) = read(&mut *port) {
-                return Ok(m);
+            if let Some(frame) = state.decoder.next_frame(self.link.signing.as_ref(), &self.link.errors, &self.link.stats) {
+                return Ok(frame);
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+            if !try!(ready_to_read(&state.socket, deadline)) {
+                continue;
+            }
+            try!(state.socket.set_read_timeout(deadline.map(read_timeout)));
+            match state.decoder.fill(&mut state.socket, &self.link.stats) {
+                Ok(()) => (),
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
+    fn write_locked(
+        &self,
+        lock: &mut UnixWrite,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        try!(lock.socket.write_all(&buf));
+        self.link.stats.record_sent(buf.len());
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl MavConnection for Unix {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
     fn send(&self, data: &MavMessage) -> io::Result<()> {
-        let mut port = self.port.lock().unwrap();
-        let mut sequence = self.sequence.lock().unwrap();
+        let mut lock = self.write.lock().unwrap();
 
-        let header = Header {
-            sequence: *sequence,
-            system_id: 255,
-            component_id: 0,
+        let header = self.link.next_header(&mut lock.sequence);
+        self.write_locked(&mut lock, self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        self.write_locked(&mut lock, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+#[cfg(unix)]
+struct UnixDgramRead {
+    socket: UnixDatagram,
+    recv_buf: PacketBuf,
+    /// Arrival time of the datagram in `recv_buf`
+    received: ReceiveTime,
+}
+
+#[cfg(unix)]
+struct UnixDgramWrite {
+    socket: UnixDatagram,
+    sequence: u8,
+}
+
+/// Unix domain datagram socket MAVLink connection.
+///
+/// The socket is bound to a path, which is removed again when the connection is dropped.
+/// Messages are sent to a fixed remote path or, without one, to the last node that sent
+/// us a datagram from a bound socket; until then they are discarded.
+#[cfg(unix)]
+pub struct UnixDgram {
+    read: Mutex<UnixDgramRead>,
+    write: Mutex<UnixDgramWrite>,
+    /// Where sent datagrams go, apart from `write` so receiving never waits for a send
+    dest: Mutex<Option<PathBuf>>,
+    /// Whether `dest` was given, rather than learned from the last sender
+    fixed: bool,
+    path: PathBuf,
+    link: LinkConfig,
+}
+
+#[cfg(unix)]
+impl UnixDgram {
+    /// Bind a datagram socket at `path`, sending to `remote` if given.
+    ///
+    /// A socket left behind at `path` by an earlier process is replaced.
+    pub fn bind<P: AsRef<Path>>(path: P, remote: Option<&Path>) -> io::Result<UnixDgram> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                try!(fs::remove_file(&path));
+            }
+        }
+        let socket = try!(UnixDatagram::bind(&path));
+        Ok(UnixDgram {
+            read: Mutex::new(UnixDgramRead {
+                socket: try!(socket.try_clone()),
+                recv_buf: PacketBuf::new(),
+                received: ReceiveTime::now(),
+            }),
+            write: Mutex::new(UnixDgramWrite {
+                socket: socket,
+                sequence: 0,
+            }),
+            dest: Mutex::new(remote.map(Path::to_path_buf)),
+            fixed: remote.is_some(),
+            path: path,
+            link: LinkConfig::new(),
+        })
+    }
+
+    /// Path the socket is bound to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        let mut attempted = false;
+        loop {
+            if state.recv_buf.len() == 0 {
+                if attempted && deadline_passed(deadline) {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                attempted = true;
+                if !try!(ready_to_read(&state.socket, deadline)) {
+                    continue;
+                }
+                try!(state.socket.set_read_timeout(deadline.map(read_timeout)));
+                let (len, src) = match state.socket.recv_from(state.recv_buf.reset()) {
+                    Ok(received) => received,
+                    Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                state.recv_buf.set_len(len);
+                state.received = ReceiveTime::now();
+                self.link.stats.record_bytes_received(len);
+
+                if let Some(src) = src.as_pathname() {
+                    if !self.fixed {
+                        *self.dest.lock().unwrap() = Some(src.to_path_buf());
+                    }
+                }
+            }
+
+            match read_frame(&mut state.recv_buf, self.link.signing.as_ref()) {
+                Ok(mut frame) => {
+                    frame.received = Some(state.received);
+                    self.link.stats.record_received(&frame.header);
+                    return Ok(frame);
+                }
+                // the rest of the datagram did not hold a complete frame
+                Err(MessageReadError::Eof) => (),
+                Err(MessageReadError::Io(e)) => return Err(e.into()),
+                Err(e) => self.link.errors.report(&e),
+            }
+        }
+    }
+
+    fn write_locked(
+        &self,
+        lock: &mut UnixDgramWrite,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let dest = match *self.dest.lock().unwrap() {
+            Some(ref dest) => dest.clone(),
+            None => return Ok(()),
         };
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        try!(lock.socket.send_to(&buf, &dest));
+        self.link.stats.record_sent(buf.len());
+        Ok(())
+    }
+}
 
-        *sequence = sequence.wrapping_add(1);
+#[cfg(unix)]
+impl Drop for UnixDgram {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+impl MavConnection for UnixDgram {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+
+        let header = self.link.next_header(&mut lock.sequence);
+        self.write_locked(&mut lock, self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        self.write_locked(&mut lock, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+#[cfg(unix)]
+struct PtyRead {
+    master: File,
+    decoder: StreamDecoder,
+}
+
+#[cfg(unix)]
+struct PtyWrite {
+    master: File,
+    /// Our own handle on the terminal side, kept open so the master does not fail with
+    /// `EIO` while no tool has the terminal open
+    slave: File,
+    sequence: u8,
+}
+
+/// Pseudo-terminal MAVLink connection.
+///
+/// Creates a pseudo-terminal pair in raw mode, so tools that only speak serial can open
+/// the terminal side as if it were a radio. Messages sent while no tool reads the terminal
+/// are dropped once its buffer is full.
+#[cfg(unix)]
+pub struct Pty {
+    read: Mutex<PtyRead>,
+    write: Mutex<PtyWrite>,
+    slave_path: PathBuf,
+    /// Symlink to the terminal side, removed on drop
+    symlink: Option<PathBuf>,
+    link: LinkConfig,
+}
+
+#[cfg(unix)]
+impl Pty {
+    /// Create a pseudo-terminal pair.
+    ///
+    /// With a `link` path, a symlink to the terminal is created there so tools can be
+    /// configured with a stable device name; it is removed when the connection is dropped.
+    pub fn open(link: Option<&Path>) -> io::Result<Pty> {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+        let fd = master.as_raw_fd();
+        if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // ptsname is not reentrant, so the name is copied right away
+        let slave_path = unsafe {
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(OsStr::from_bytes(CStr::from_ptr(name).to_bytes()))
+        };
+
+        let slave = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&slave_path));
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // writes must not block while nobody reads the terminal; reads wait in poll
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if let Some(link) = link {
+            if let Ok(metadata) = fs::symlink_metadata(link) {
+                if metadata.file_type().is_symlink() {
+                    try!(fs::remove_file(link));
+                }
+            }
+            try!(symlink(&slave_path, link));
+        }
+
+        Ok(Pty {
+            read: Mutex::new(PtyRead {
+                master: try!(master.try_clone()),
+                decoder: StreamDecoder::new(),
+            }),
+            write: Mutex::new(PtyWrite {
+                master: master,
+                slave: slave,
+                sequence: 0,
+            }),
+            slave_path: slave_path,
+            symlink: link.map(Path::to_path_buf),
+            link: LinkConfig::new(),
+        })
+    }
+
+    /// Path of the terminal side, for tools to open
+    pub fn slave_path(&self) -> &Path {
+        &self.slave_path
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        let mut attempted = false;
+        loop {
+            if let Some(frame) = state.decoder.next_frame(self.link.signing.as_ref(), &self.link.errors, &self.link.stats) {
+                return Ok(frame);
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+            if !try!(wait_readable(state.master.as_raw_fd(), deadline)) {
+                continue;
+            }
+            match state.decoder.fill(&mut state.master, &self.link.stats) {
+                Ok(()) => (),
+                Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_locked(
+        &self,
+        lock: &mut PtyWrite,
+        version: MavlinkVersion,
+        header: Header,
+        data: &MavMessage,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+        let mut written = 0;
+        while written < buf.len() {
+            match lock.master.write(&buf[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // nobody reads the terminal; drop what is queued for it, like a radio
+                    // link with no listener
+                    if unsafe { libc::tcflush(lock.slave.as_raw_fd(), libc::TCIFLUSH) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // the start of this frame was dropped too, send all of it again
+                    written = 0;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.link.stats.record_sent(buf.len());
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(ref symlink) = self.symlink {
+            let _ = fs::remove_file(symlink);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl MavConnection for Pty {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+
+        let header = self.link.next_header(&mut lock.sequence);
+        self.write_locked(&mut lock, self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut lock = self.write.lock().unwrap();
+        self.write_locked(&mut lock, frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+/// Longest a `TcpServer` waits for a client to take a frame before dropping the client
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Event from the client threads of a `TcpServer`
+enum ClientEvent {
+    Data(usize, Vec<u8>, ReceiveTime),
+    Closed(usize),
+}
+
+struct TcpClient {
+    id: usize,
+    addr: SocketAddr,
+    /// Shared with senders, which write to it without holding the client list
+    socket: Arc<TcpStream>,
+}
+
+struct TcpServerRead {
+    events: mpsc::Receiver<ClientEvent>,
+    decoders: HashMap<usize, StreamDecoder>,
+    /// Client whose decoder may still hold complete frames
+    current: Option<usize>,
+}
+
+/// TCP server MAVLink connection accepting any number of clients.
+///
+/// Frames received from all clients are merged into `recv`, and sent messages go out to
+/// every connected client. Clients that disconnect, fail a write or do not take a frame
+/// within `CLIENT_WRITE_TIMEOUT` are dropped without affecting the others.
+pub struct TcpServer {
+    clients: Arc<Mutex<Vec<TcpClient>>>,
+    /// Tells the accepting thread to stop once the server is dropped
+    shutdown: Arc<AtomicBool>,
+    read: Mutex<TcpServerRead>,
+    /// Held while writing, so frames sent from several threads do not interleave
+    sequence: Mutex<u8>,
+    local_addr: SocketAddr,
+    link: LinkConfig,
+}
 
-        try!(write(&mut *port, header, data));
+impl TcpServer {
+    pub fn tcpin<T: ToSocketAddrs>(address: T) -> io::Result<TcpServer> {
+        let listener = try!(TcpListener::bind(address));
+        let local_addr = try!(listener.local_addr());
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let (events_tx, events_rx) = mpsc::channel();
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let weak_clients = Arc::downgrade(&clients);
+        let accept_shutdown = shutdown.clone();
+        thread::spawn(move || accept_clients(listener, weak_clients, events_tx, accept_shutdown));
+
+        Ok(TcpServer {
+            clients: clients,
+            shutdown: shutdown,
+            read: Mutex::new(TcpServerRead {
+                events: events_rx,
+                decoders: HashMap::new(),
+                current: None,
+            }),
+            sequence: Mutex::new(0),
+            local_addr: local_addr,
+            link: LinkConfig::new(),
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Addresses of the currently connected clients
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        self.clients.lock().unwrap().iter().map(|client| client.addr).collect()
+    }
+
+    /// Receive a frame from any client, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        let mut attempted = false;
+        loop {
+            if let Some(id) = state.current {
+                if let Some(decoder) = state.decoders.get_mut(&id) {
+                    if let Some(frame) = decoder.next_frame(self.link.signing.as_ref(), &self.link.errors, &self.link.stats) {
+                        return Ok(frame);
+                    }
+                }
+                state.current = None;
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+
+            let event = match deadline {
+                Some(deadline) if deadline_passed(Some(deadline)) => match state.events.try_recv() {
+                    Ok(event) => event,
+                    Err(mpsc::TryRecvError::Empty) => continue,
+                    Err(mpsc::TryRecvError::Disconnected) => return Err(listener_closed()),
+                },
+                Some(deadline) => match state.events.recv_timeout(read_timeout(deadline)) {
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Err(listener_closed()),
+                },
+                None => match state.events.recv() {
+                    Ok(event) => event,
+                    Err(_) => return Err(listener_closed()),
+                },
+            };
+            match event {
+                ClientEvent::Data(id, data, received) => {
+                    let decoder = state.decoders.entry(id).or_insert_with(StreamDecoder::new);
+                    decoder.push(&data, received, &self.link.stats);
+                    state.current = Some(id);
+                }
+                ClientEvent::Closed(id) => {
+                    state.decoders.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Write a frame to every client, dropping the clients that fail
+    fn write_all_clients(&self, version: MavlinkVersion, header: Header, data: &MavMessage) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(write_signed(&mut buf, version, header, data, self.link.signing.as_ref()));
+
+        // a stalled client must not hold up accepting new ones
+        let clients: Vec<(usize, Arc<TcpStream>)> = self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|client| (client.id, client.socket.clone()))
+            .collect();
+        let mut failed = Vec::new();
+        for &(id, ref socket) in clients.iter() {
+            if (&**socket).write_all(&buf).is_err() {
+                // wakes up the client thread, which reports the client as closed
+                let _ = socket.shutdown(Shutdown::Both);
+                failed.push(id);
+            }
+        }
+        if !failed.is_empty() {
+            self.clients.lock().unwrap().retain(|client| !failed.contains(&client.id));
+        }
+        let sent = clients.len() - failed.len();
+        if sent > 0 {
+            self.link.stats.record_sent(buf.len() * sent);
+        }
         Ok(())
     }
 }
+
+fn listener_closed() -> RecvTimeoutError {
+    RecvTimeoutError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "listener closed"))
+}
+
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake the accepting thread, which closes the listener
+        let ip = match self.local_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            ip => ip,
+        };
+        let _ = TcpStream::connect_timeout(&SocketAddr::new(ip, self.local_addr.port()), Duration::from_secs(1));
+        // and the client threads
+        for client in self.clients.lock().unwrap().iter() {
+            let _ = client.socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Accept clients until the server is dropped, reading each one on its own thread
+fn accept_clients(
+    listener: TcpListener,
+    clients: Weak<Mutex<Vec<TcpClient>>>,
+    events: mpsc::Sender<ClientEvent>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let mut next_id = 0;
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        let clients = match clients.upgrade() {
+            Some(clients) => clients,
+            None => return,
+        };
+        let socket = match stream {
+            Ok(socket) => socket,
+            Err(_) => continue,
+        };
+        // a stalled client must not hold up sends to the others
+        if socket.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)).is_err() {
+            continue;
+        }
+        let (addr, reader) = match (socket.peer_addr(), socket.try_clone()) {
+            (Ok(addr), Ok(reader)) => (addr, reader),
+            _ => continue,
+        };
+
+        let id = next_id;
+        next_id += 1;
+        clients.lock().unwrap().push(TcpClient {
+            id: id,
+            addr: addr,
+            socket: Arc::new(socket),
+        });
+
+        let events = events.clone();
+        let clients = Arc::downgrade(&clients);
+        thread::spawn(move || read_client(id, reader, clients, events));
+    }
+}
+
+/// Forward the bytes read from one client until it disconnects
+fn read_client(id: usize, mut socket: TcpStream, clients: Weak<Mutex<Vec<TcpClient>>>, events: mpsc::Sender<ClientEvent>) {
+    let mut buf = vec![0; STREAM_READ_CHUNK];
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let event = ClientEvent::Data(id, buf[..n].to_vec(), ReceiveTime::now());
+                if events.send(event).is_err() {
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => break,
+        }
+    }
+    if let Some(clients) = clients.upgrade() {
+        clients.lock().unwrap().retain(|client| client.id != id);
+    }
+    let _ = events.send(ClientEvent::Closed(id));
+}
+
+impl MavConnection for TcpServer {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let header = self.link.next_header(&mut *sequence);
+        self.write_all_clients(self.link.protocol_version, header, data)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let _sequence = self.sequence.lock().unwrap();
+        self.write_all_clients(frame.version, frame.header, &frame.msg)
+    }
+
+    link_config_methods!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serial_error(settings: &str) -> String {
+        let e = SerialConfig::parse(settings).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        e.to_string()
+    }
+
+    fn address_error(address: &str) -> String {
+        match parse_address(address) {
+            Ok(_) => panic!("'{}' was accepted", address),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn address_parameters() {
+        let (address, options) = parse_address("udpout:127.0.0.1:14550").unwrap();
+        assert_eq!(address, "udpout:127.0.0.1:14550");
+        assert_eq!((options.system_id, options.component_id), (None, None));
+        assert_eq!((options.interface, options.reconnect, options.speed), (None, false, None));
+
+        let (address, options) =
+            parse_address("udpmcast:239.255.145.50:14550?sysid=255&compid=0&iface=192.168.1.2&reconnect=true")
+                .unwrap();
+        assert_eq!(address, "udpmcast:239.255.145.50:14550");
+        assert_eq!((options.system_id, options.component_id), (Some(255), Some(0)));
+        assert_eq!(options.interface, Some(Ipv4Addr::new(192, 168, 1, 2)));
+        assert!(options.reconnect);
+
+        let (address, options) = parse_address("file:flight.tlog?speed=0.5&reconnect=0&").unwrap();
+        assert_eq!(address, "file:flight.tlog");
+        assert_eq!((options.speed, options.reconnect), (Some(0.5), false));
+    }
+
+    #[test]
+    fn malformed_address_parameters() {
+        assert!(address_error("tcp:localhost:5760?sysid=256").contains("Invalid sysid '256'"));
+        assert!(address_error("tcp:localhost:5760?sysid=-1").contains("Invalid sysid '-1'"));
+        assert!(address_error("tcp:localhost:5760?compid").contains("Invalid compid ''"));
+        assert!(address_error("tcp:localhost:5760?reconnect=yes").contains("Invalid reconnect 'yes'"));
+        assert!(address_error("udpmcast:239.255.145.50:14550?iface=eth0").contains("Invalid iface 'eth0'"));
+        assert!(address_error("file:flight.tlog?speed=0").contains("Invalid speed '0'"));
+        assert!(address_error("file:flight.tlog?speed=fast").contains("Invalid speed 'fast'"));
+        assert!(address_error("tcp:localhost:5760?sysid=1&baud=57600").contains("Unknown address parameter 'baud'"));
+    }
+
+    #[test]
+    fn address_paths_containing_question_marks() {
+        let (address, options) = parse_address("unix:/tmp/what?.sock?sysid=3").unwrap();
+        assert_eq!(address, "unix:/tmp/what?.sock");
+        assert_eq!(options.system_id, Some(3));
+
+        let (address, options) = parse_address("file:/logs/what?.tlog?").unwrap();
+        assert_eq!(address, "file:/logs/what?.tlog");
+        assert_eq!(options.speed, None);
+    }
+
+    #[test]
+    fn serial_settings_round_trip() {
+        let config = SerialConfig::parse("/dev/ttyUSB0:57600:7e2:RTSCTS").unwrap();
+        assert_eq!(config.port, "/dev/ttyUSB0");
+        assert_eq!(config.baud, 57600);
+        assert_eq!(config.char_size, ::serial::Bits7);
+        assert_eq!(config.parity, ::serial::ParityEven);
+        assert_eq!(config.stop_bits, ::serial::Stop2);
+        assert_eq!(config.flow_control, ::serial::FlowHardware);
+        assert_eq!(config.to_string(), "/dev/ttyUSB0:57600:7E2:rtscts");
+        assert_eq!(SerialConfig::parse(&config.to_string()).unwrap(), config);
+
+        // flow control alone, without framing
+        let config = SerialConfig::parse("/dev/ttyACM0:115200:xonxoff").unwrap();
+        assert_eq!(config.to_string(), "/dev/ttyACM0:115200:8N1:xonxoff");
+    }
+
+    #[test]
+    fn serial_settings_default_to_8n1() {
+        let config = SerialConfig::parse("/dev/ttyUSB0:921600").unwrap();
+        assert_eq!(config, SerialConfig::new("/dev/ttyUSB0", 921600));
+        assert_eq!(config.to_string(), "/dev/ttyUSB0:921600:8N1:none");
+    }
+
+    #[test]
+    fn malformed_serial_settings() {
+        assert!(serial_error(":57600").contains("missing port"));
+        assert!(serial_error("/dev/ttyUSB0").contains("missing baud rate"));
+        assert!(serial_error("/dev/ttyUSB0:abc").contains("invalid baud rate 'abc'"));
+        assert!(serial_error("/dev/ttyUSB0:0").contains("invalid baud rate '0'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:9N1").contains("invalid data bits '9'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:8X1").contains("invalid parity 'X'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:8N3").contains("invalid stop bits '3'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:8N").contains("invalid framing '8N'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:8N1:dtrdsr").contains("invalid flow control 'dtrdsr'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:8N1:7E1").contains("unexpected setting '7E1'"));
+        assert!(serial_error("/dev/ttyUSB0:57600:none:rtscts").contains("unexpected setting 'rtscts'"));
+    }
+}
@@ -0,0 +1,361 @@
+use std::io::Cursor;
+
+use {read_v1_raw, read_v2_raw, MavFrame, RawFrame, SigningData, MAVLINK_IFLAG_SIGNED, MAV_STX, MAV_STX_V2};
+use error::MessageReadError;
+use signing::SIGNATURE_LEN;
+
+/// Incremental MAVLink frame decoder.
+///
+/// Bytes are pushed in arbitrary chunks as they arrive and complete frames are pulled
+/// out with `next_frame`. A partially received frame is kept until the rest of it is
+/// pushed, so one decoder can be driven from an event loop without blocking.
+///
+/// When a frame fails its CRC check, or its message id is unknown so the CRC cannot be
+/// checked, the start byte is assumed to be a false match and scanning resumes from
+/// the byte right after it.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    start: usize,
+    /// No bytes follow the buffered ones, see `end_of_input`
+    at_end: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buf: Vec::new(),
+            start: 0,
+            at_end: false,
+        }
+    }
+
+    /// Append received bytes
+    pub fn push(&mut self, data: &[u8]) {
+        // drop consumed bytes before growing the buffer
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(data);
+        self.at_end = false;
+    }
+
+    /// Mark the end of the input, such as the end of a datagram or a closed stream, so
+    /// `next_raw_frame` can pass through an unknown message ending with the buffered
+    /// bytes. Pushing more bytes clears the mark.
+    pub fn end_of_input(&mut self) {
+        self.at_end = true;
+    }
+
+    /// Number of buffered bytes not consumed yet
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// Discard all buffered bytes, including a partially received frame
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+        self.at_end = false;
+    }
+
+    /// Decode the next frame from the buffered bytes.
+    ///
+    /// Returns `None` when more bytes are needed. Frames that cannot be decoded are
+    /// returned as errors; call again to continue with the following bytes. Signatures
+    /// are checked as in `read_signed`.
+    pub fn next_frame(&mut self, signing: Option<&SigningData>) -> Option<Result<MavFrame, MessageReadError>> {
+        let (raw, frame_len) = match self.peek_raw() {
+            Some(raw) => raw,
+            None => return None,
+        };
+        let result = raw.to_frame(signing);
+        match result {
+            // the start byte was likely part of another frame, rescan after it
+            Err(MessageReadError::BadCrc { .. }) | Err(MessageReadError::UnknownMessage { .. }) => {
+                self.start += 1;
+            }
+            _ => self.start += frame_len,
+        }
+        Some(result)
+    }
+
+    /// Take the next frame from the buffered bytes without decoding it.
+    ///
+    /// Frames with message ids outside the compiled dialect cannot have their CRC checked,
+    /// so they are passed through only if they are followed by a start byte, or by the end
+    /// of the input marked with `end_of_input`. Otherwise the start byte is taken for line
+    /// noise, so it does not swallow the frames after it. Returns `None` when more bytes
+    /// are needed.
+    pub fn next_raw_frame(&mut self) -> Option<Result<RawFrame, MessageReadError>> {
+        let (raw, frame_len) = match self.peek_raw() {
+            Some(raw) => raw,
+            None => return None,
+        };
+        if raw.is_known() {
+            if let Err(e) = raw.check_crc() {
+                self.start += 1;
+                return Some(Err(e));
+            }
+        } else {
+            match self.buf.get(self.start + frame_len) {
+                Some(&MAV_STX) | Some(&MAV_STX_V2) => (),
+                None if self.at_end => (),
+                None => return None,
+                Some(_) => {
+                    self.start += 1;
+                    return Some(Err(MessageReadError::UnknownMessage { msgid: raw.msgid }));
+                }
+            }
+        }
+        self.start += frame_len;
+        Some(Ok(raw))
+    }
+
+    /// Find the next complete frame without consuming it, skipping bytes before its start byte
+    fn peek_raw(&mut self) -> Option<(RawFrame, usize)> {
+        let pending = &self.buf[self.start..];
+        let stx_pos = match pending.iter().position(|&b| b == MAV_STX || b == MAV_STX_V2) {
+            Some(pos) => pos,
+            None => {
+                self.start = self.buf.len();
+                return None;
+            }
+        };
+        self.start += stx_pos;
+        let pending = &pending[stx_pos..];
+
+        let frame_len = match frame_len(pending) {
+            Some(len) => len,
+            None => return None,
+        };
+        if pending.len() < frame_len {
+            return None;
+        }
+
+        // the frame is complete, so reading it from the buffer cannot fail
+        let mut cur = Cursor::new(&pending[1..frame_len]);
+        let raw = if pending[0] == MAV_STX {
+            read_v1_raw(&mut cur)
+        } else {
+            read_v2_raw(&mut cur)
+        };
+        raw.ok().map(|raw| (raw, frame_len))
+    }
+}
+
+/// Total length of the frame starting at `pending[0]`, or `None` if the header is incomplete
+fn frame_len(pending: &[u8]) -> Option<usize> {
+    if pending[0] == MAV_STX {
+        // stx, header, payload, crc
+        pending.get(1).map(|&len| 1 + 5 + len as usize + 2)
+    } else {
+        if pending.len() < 3 {
+            return None;
+        }
+        let len = pending[1] as usize;
+        let signature_len = if pending[2] & MAVLINK_IFLAG_SIGNED != 0 { SIGNATURE_LEN } else { 0 };
+        Some(1 + 9 + len + 2 + signature_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{HEARTBEAT_DATA, MavMessage};
+    use {write_raw, write_versioned, Header, MavlinkVersion};
+
+    fn heartbeat(version: MavlinkVersion, sequence: u8) -> Vec<u8> {
+        let header = Header {
+            sequence: sequence,
+            system_id: 1,
+            component_id: 1,
+        };
+        let mut buf = Vec::new();
+        write_versioned(&mut buf, version, header, &MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())).unwrap();
+        buf
+    }
+
+    /// Decode every frame in the buffer, returning the sequence numbers and the number of errors
+    fn decode_all(decoder: &mut FrameDecoder) -> (Vec<u8>, usize) {
+        let mut sequences = Vec::new();
+        let mut errors = 0;
+        while let Some(result) = decoder.next_frame(None) {
+            match result {
+                Ok(frame) => sequences.push(frame.header.sequence),
+                Err(_) => errors += 1,
+            }
+        }
+        (sequences, errors)
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0x00, 0x11, 0x22, 0x33]);
+        decoder.push(&heartbeat(MavlinkVersion::V1, 1));
+        decoder.push(&[0x55; 20]);
+        decoder.push(&heartbeat(MavlinkVersion::V2, 2));
+        assert_eq!(decode_all(&mut decoder), (vec![1, 2], 0));
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn skips_false_start_byte() {
+        let mut decoder = FrameDecoder::new();
+        // a start byte in line noise, claiming a frame that runs into the real one
+        decoder.push(&[MAV_STX, 3, 0x10, 0x20]);
+        decoder.push(&heartbeat(MavlinkVersion::V1, 1));
+        decoder.push(&[MAV_STX_V2, 0x02]);
+        decoder.push(&heartbeat(MavlinkVersion::V2, 2));
+        decoder.push(&heartbeat(MavlinkVersion::V1, 3));
+        let (sequences, errors) = decode_all(&mut decoder);
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert!(errors >= 1);
+    }
+
+    #[test]
+    fn waits_for_truncated_frame() {
+        let frame = heartbeat(MavlinkVersion::V2, 7);
+        let mut decoder = FrameDecoder::new();
+        // header incomplete, then payload incomplete
+        decoder.push(&frame[..2]);
+        assert!(decoder.next_frame(None).is_none());
+        decoder.push(&frame[2..frame.len() - 1]);
+        assert!(decoder.next_frame(None).is_none());
+        decoder.push(&frame[frame.len() - 1..]);
+        assert_eq!(decode_all(&mut decoder), (vec![7], 0));
+    }
+
+    #[test]
+    fn recovers_from_frame_cut_short() {
+        let first = heartbeat(MavlinkVersion::V1, 1);
+        let mut decoder = FrameDecoder::new();
+        // the end of the first frame was lost on the wire
+        decoder.push(&first[..first.len() - 3]);
+        decoder.push(&heartbeat(MavlinkVersion::V1, 2));
+        decoder.push(&heartbeat(MavlinkVersion::V1, 3));
+        let (sequences, errors) = decode_all(&mut decoder);
+        assert_eq!(sequences, vec![2, 3]);
+        assert_eq!(errors, 1);
+    }
+
+    /// MAVLink 2 frame of a message outside the dialect
+    fn unknown_frame(sequence: u8) -> Vec<u8> {
+        let unknown = RawFrame {
+            header: Header {
+                sequence: sequence,
+                system_id: 1,
+                component_id: 1,
+            },
+            version: MavlinkVersion::V2,
+            incompat_flags: 0,
+            compat_flags: 0,
+            msgid: 0xFFFF00,
+            payload: vec![1, 2, 3],
+            checksum: 0x1234,
+            signature: None,
+        };
+        let mut frame = Vec::new();
+        write_raw(&mut frame, &unknown).unwrap();
+        frame
+    }
+
+    #[test]
+    fn passes_through_unknown_messages() {
+        let frame = unknown_frame(5);
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame);
+        decoder.push(&heartbeat(MavlinkVersion::V1, 6));
+        decoder.push(&frame);
+        let msgids: Vec<u32> = (0..2).map(|_| decoder.next_raw_frame().unwrap().unwrap().msgid).collect();
+        assert_eq!(msgids, vec![0xFFFF00, 0]);
+        // the last frame could still run on into bytes not received yet
+        assert!(decoder.next_raw_frame().is_none());
+        decoder.end_of_input();
+        assert_eq!(decoder.next_raw_frame().unwrap().unwrap().msgid, 0xFFFF00);
+        assert!(decoder.next_raw_frame().is_none());
+    }
+
+    #[test]
+    fn unknown_message_does_not_swallow_next_frame() {
+        let mut decoder = FrameDecoder::new();
+        // noise that reads as a frame of an unknown message, ending inside the heartbeat
+        decoder.push(&[MAV_STX, 2, 0, 0, 0, 0xEE, 0, 0]);
+        decoder.push(&heartbeat(MavlinkVersion::V1, 1));
+        decoder.push(&heartbeat(MavlinkVersion::V1, 2));
+        let mut sequences = Vec::new();
+        while let Some(result) = decoder.next_raw_frame() {
+            if let Ok(raw) = result {
+                sequences.push(raw.header.sequence);
+            }
+        }
+        assert_eq!(sequences, vec![1, 2]);
+    }
+
+    #[test]
+    fn unknown_message_framing_does_not_depend_on_chunks() {
+        let mut stream = vec![0x11, 0x22];
+        // noise that reads as a frame of an unknown message, ending inside the next frame
+        stream.extend(&[MAV_STX, 2, 0, 0, 0, 0xEE, 0, 0]);
+        stream.extend(unknown_frame(4));
+        stream.extend(heartbeat(MavlinkVersion::V1, 5));
+        stream.extend(unknown_frame(6));
+
+        let decode = |chunks: &[&[u8]]| {
+            let mut decoder = FrameDecoder::new();
+            let mut sequences = Vec::new();
+            for chunk in chunks {
+                decoder.push(chunk);
+                while let Some(result) = decoder.next_raw_frame() {
+                    if let Ok(raw) = result {
+                        sequences.push(raw.header.sequence);
+                    }
+                }
+            }
+            decoder.end_of_input();
+            while let Some(result) = decoder.next_raw_frame() {
+                if let Ok(raw) = result {
+                    sequences.push(raw.header.sequence);
+                }
+            }
+            sequences
+        };
+
+        assert_eq!(decode(&[&stream]), vec![4, 5, 6]);
+        for split in 1..stream.len() {
+            assert_eq!(decode(&[&stream[..split], &stream[split..]]), vec![4, 5, 6], "split at {}", split);
+        }
+        let bytes: Vec<&[u8]> = stream.chunks(1).collect();
+        assert_eq!(decode(&bytes), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn decodes_mixed_versions() {
+        let mut stream = Vec::new();
+        stream.extend(heartbeat(MavlinkVersion::V1, 1));
+        stream.extend(heartbeat(MavlinkVersion::V2, 2));
+        stream.extend(heartbeat(MavlinkVersion::V2, 3));
+        stream.extend(heartbeat(MavlinkVersion::V1, 4));
+
+        let mut decoder = FrameDecoder::new();
+        // in small chunks, as from a serial port
+        for chunk in stream.chunks(5) {
+            decoder.push(chunk);
+        }
+        let mut versions = Vec::new();
+        while let Some(result) = decoder.next_frame(None) {
+            let frame = result.unwrap();
+            versions.push((frame.header.sequence, frame.version));
+        }
+        assert_eq!(
+            versions,
+            vec![
+                (1, MavlinkVersion::V1),
+                (2, MavlinkVersion::V2),
+                (3, MavlinkVersion::V2),
+                (4, MavlinkVersion::V1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Error reading a MAVLink frame
+#[derive(Debug)]
+pub enum MessageReadError {
+    /// The underlying stream failed
+    Io(io::Error),
+    /// The stream ended before a complete frame was read
+    Eof,
+    /// The frame checksum does not match its contents
+    BadCrc { msgid: u32, expected: u16, actual: u16 },
+    /// The message id is not part of the compiled dialect
+    UnknownMessage { msgid: u32 },
+    /// A MAVLink 1 payload is shorter than the message requires
+    PayloadTooShort { msgid: u32, len: usize, expected: usize },
+    /// The frame signature is invalid, replayed, or missing on a link that requires it
+    SignatureRejected { msgid: u32 },
+}
+
+impl fmt::Display for MessageReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageReadError::Io(ref e) => write!(f, "I/O error: {}", e),
+            MessageReadError::Eof => write!(f, "unexpected end of stream"),
+            MessageReadError::BadCrc { msgid, expected, actual } => write!(
+                f,
+                "bad CRC for message {}: expected {:#06x}, got {:#06x}",
+                msgid, expected, actual
+            ),
+            MessageReadError::UnknownMessage { msgid } => write!(f, "unknown message id {}", msgid),
+            MessageReadError::PayloadTooShort { msgid, len, expected } => write!(
+                f,
+                "payload of message {} is {} bytes, expected {}",
+                msgid, len, expected
+            ),
+            MessageReadError::SignatureRejected { msgid } => {
+                write!(f, "signature of message {} rejected", msgid)
+            }
+        }
+    }
+}
+
+impl Error for MessageReadError {
+    fn description(&self) -> &str {
+        match *self {
+            MessageReadError::Io(ref e) => e.description(),
+            MessageReadError::Eof => "unexpected end of stream",
+            MessageReadError::BadCrc { .. } => "bad CRC",
+            MessageReadError::UnknownMessage { .. } => "unknown message id",
+            MessageReadError::PayloadTooShort { .. } => "payload too short",
+            MessageReadError::SignatureRejected { .. } => "signature rejected",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            MessageReadError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MessageReadError {
+    fn from(e: io::Error) -> MessageReadError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            MessageReadError::Eof
+        } else {
+            MessageReadError::Io(e)
+        }
+    }
+}
+
+impl From<MessageReadError> for io::Error {
+    fn from(e: MessageReadError) -> io::Error {
+        match e {
+            MessageReadError::Io(e) => e,
+            MessageReadError::Eof => io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of stream"),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Error receiving a frame within a time limit
+#[derive(Debug)]
+pub enum RecvTimeoutError {
+    /// No frame arrived before the timeout expired
+    Timeout,
+    /// The link failed
+    Io(io::Error),
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvTimeoutError::Timeout => write!(f, "timed out waiting for a frame"),
+            RecvTimeoutError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for RecvTimeoutError {
+    fn description(&self) -> &str {
+        match *self {
+            RecvTimeoutError::Timeout => "timed out waiting for a frame",
+            RecvTimeoutError::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            RecvTimeoutError::Io(ref e) => Some(e),
+            RecvTimeoutError::Timeout => None,
+        }
+    }
+}
+
+impl From<io::Error> for RecvTimeoutError {
+    fn from(e: io::Error) -> RecvTimeoutError {
+        RecvTimeoutError::Io(e)
+    }
+}
+
+impl From<RecvTimeoutError> for io::Error {
+    fn from(e: RecvTimeoutError) -> io::Error {
+        match e {
+            RecvTimeoutError::Io(e) => e,
+            RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a frame"),
+        }
+    }
+}
+
+/// Number of frames skipped in lenient mode, by reason
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ReadErrorCounts {
+    pub bad_crc: u64,
+    pub unknown_message: u64,
+    pub payload_too_short: u64,
+    pub signature_rejected: u64,
+}
+
+impl ReadErrorCounts {
+    /// Count a skipped frame
+    pub fn record(&mut self, e: &MessageReadError) {
+        match *e {
+            MessageReadError::BadCrc { .. } => self.bad_crc += 1,
+            MessageReadError::UnknownMessage { .. } => self.unknown_message += 1,
+            MessageReadError::PayloadTooShort { .. } => self.payload_too_short += 1,
+            MessageReadError::SignatureRejected { .. } => self.signature_rejected += 1,
+            MessageReadError::Io(_) | MessageReadError::Eof => (),
+        }
+    }
+}
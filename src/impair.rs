@@ -0,0 +1,441 @@
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::MavMessage;
+use connection::{deadline_passed, Connection, ErrorHandler, LinkConfig, MavConnection};
+use error::{ReadErrorCounts, RecvTimeoutError};
+use schedule::Schedule;
+use stats::LinkStats;
+use {MavFrame, MavlinkVersion, ReceiveTime, SigningConfig};
+
+/// Impairments applied to the frames of one direction of an `ImpairedConnection`.
+///
+/// Probabilities are per frame, from 0 to 1. The default impairs nothing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Impairment {
+    /// Probability that a frame is lost
+    pub loss: f64,
+    /// Delay added to every frame
+    pub latency: Duration,
+    /// Largest random delay added on top of the latency
+    pub jitter: Duration,
+    /// Probability that a frame is delivered twice
+    pub duplicate: f64,
+    /// Probability that a frame is held back by `reorder_delay`, so later frames overtake it
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+}
+
+impl Default for Impairment {
+    fn default() -> Impairment {
+        Impairment {
+            loss: 0.0,
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Number of frames impaired in one direction of an `ImpairedConnection`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ImpairmentStats {
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// xorshift64* generator, so impairments are reproducible from a seed
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the all-zero state would only ever produce zeros
+        Rng {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    /// Uniform in [0, max]
+    fn duration(&mut self, max: Duration) -> Duration {
+        let max_ns = max.as_secs() * 1_000_000_000 + max.subsec_nanos() as u64;
+        if max_ns == 0 {
+            return max;
+        }
+        let ns = self.next_u64() % (max_ns + 1);
+        Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32)
+    }
+}
+
+/// Impairment state of one direction
+struct Direction {
+    config: Impairment,
+    rng: Rng,
+    stats: ImpairmentStats,
+}
+
+impl Direction {
+    fn new(seed: u64) -> Direction {
+        Direction {
+            config: Impairment::default(),
+            rng: Rng::new(seed),
+            stats: ImpairmentStats::default(),
+        }
+    }
+
+    /// Apply the impairments to a frame, returning the frames to deliver and when
+    fn impair(&mut self, frame: &MavFrame, now: Instant) -> Vec<(Instant, MavFrame)> {
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return Vec::new();
+        }
+
+        let copies = if self.rng.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let mut delivered = Vec::new();
+        for _ in 0..copies {
+            let mut due = now + self.config.latency + self.rng.duration(self.config.jitter);
+            if self.rng.chance(self.config.reorder) {
+                self.stats.reordered += 1;
+                due += self.config.reorder_delay;
+            }
+            delivered.push((due, frame.clone()));
+        }
+        delivered
+    }
+}
+
+struct Outbound {
+    schedule: Mutex<Schedule>,
+    changed: Condvar,
+}
+
+/// Send delayed frames when they are due, until the connection is dropped
+fn send_delayed(inner: Arc<RwLock<Connection>>, outbound: Arc<Outbound>) {
+    let mut schedule = outbound.schedule.lock().unwrap();
+    loop {
+        if schedule.closed {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(frame) = schedule.pop_due(now) {
+            drop(schedule);
+            // a failing link loses the frame, like a radio would
+            let _ = inner.read().unwrap().send_frame(&frame);
+            schedule = outbound.schedule.lock().unwrap();
+            continue;
+        }
+        schedule = match schedule.next_due() {
+            Some(due) => outbound.changed.wait_timeout(schedule, due - now).unwrap().0,
+            None => outbound.changed.wait(schedule).unwrap(),
+        };
+    }
+}
+
+/// Connection wrapper simulating a bad link, for testing.
+///
+/// Frames in each direction can be lost, delayed with jitter, duplicated and reordered,
+/// as configured by an `Impairment` per direction.
+///
+/// The impairments apply to whole frames: received frames after the wrapped connection
+/// decoded them, sent frames before it encodes them. Byte corruption is not simulated,
+/// since it would never reach the decoder of the wrapped link; to test CRC checks and
+/// resyncing, corrupt the bytes at the transport, for example by writing them to the
+/// terminal side of a `Pty`.
+///
+/// The random choices come from a generator seeded at creation, so a run can be
+/// reproduced as long as the same frames pass in the same order. Delayed outgoing frames
+/// are sent from a background thread, and errors sending them are ignored.
+pub struct ImpairedConnection {
+    inner: Arc<RwLock<Connection>>,
+    inbound: Mutex<Direction>,
+    /// Inbound frames waiting for their delivery time
+    received: Mutex<Schedule>,
+    outbound: Mutex<Direction>,
+    delayed: Arc<Outbound>,
+    sequence: Mutex<u8>,
+    /// Source ids and protocol version of sent messages, kept in step with the wrapped
+    /// connection
+    link: LinkConfig,
+}
+
+impl ImpairedConnection {
+    /// Wrap a connection, seeding the random impairments with `seed`.
+    ///
+    /// Nothing is impaired until `set_inbound` or `set_outbound` is called.
+    pub fn new(conn: Connection, seed: u64) -> ImpairedConnection {
+        let mut link = LinkConfig::new();
+        let (system_id, component_id) = conn.get_source_ids();
+        link.system_id = system_id;
+        link.component_id = component_id;
+        link.protocol_version = conn.get_protocol_version();
+        let inner = Arc::new(RwLock::new(conn));
+        let delayed = Arc::new(Outbound {
+            schedule: Mutex::new(Schedule::new()),
+            changed: Condvar::new(),
+        });
+        thread::spawn({
+            let inner = inner.clone();
+            let delayed = delayed.clone();
+            move || send_delayed(inner, delayed)
+        });
+        ImpairedConnection {
+            inner: inner,
+            // each direction has its own generator, so one does not shift the other
+            inbound: Mutex::new(Direction::new(seed)),
+            received: Mutex::new(Schedule::new()),
+            outbound: Mutex::new(Direction::new(seed ^ 0x5555_5555_5555_5555)),
+            delayed: delayed,
+            sequence: Mutex::new(0),
+            link: link,
+        }
+    }
+
+    /// Set the impairments of received frames
+    pub fn set_inbound(&mut self, impairment: Impairment) {
+        self.inbound.lock().unwrap().config = impairment;
+    }
+
+    /// Set the impairments of sent frames
+    pub fn set_outbound(&mut self, impairment: Impairment) {
+        self.outbound.lock().unwrap().config = impairment;
+    }
+
+    pub fn inbound_stats(&self) -> ImpairmentStats {
+        self.inbound.lock().unwrap().stats
+    }
+
+    pub fn outbound_stats(&self) -> ImpairmentStats {
+        self.outbound.lock().unwrap().stats
+    }
+
+    /// Receive a frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut received = self.received.lock().unwrap();
+        let mut attempted = false;
+        loop {
+            let now = Instant::now();
+            if let Some(mut frame) = received.pop_due(now) {
+                frame.received = Some(ReceiveTime::now());
+                return Ok(frame);
+            }
+            if attempted && deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            attempted = true;
+
+            // wait for the link until the deadline or the next delayed frame is due
+            let wake = match (deadline, received.next_due()) {
+                (Some(deadline), Some(due)) => Some(cmp::min(deadline, due)),
+                (deadline, due) => deadline.or(due),
+            };
+            let inner = self.inner.read().unwrap();
+            let result = match wake {
+                Some(wake) => inner.recv_timeout(wake - cmp::min(wake, now)),
+                None => inner.recv_frame().map_err(RecvTimeoutError::from),
+            };
+            match result {
+                Ok(frame) => {
+                    let now = Instant::now();
+                    let delivered = self.inbound.lock().unwrap().impair(&frame, now);
+                    for (due, frame) in delivered {
+                        received.push(due, frame);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send_impaired(&self, frame: &MavFrame) -> io::Result<()> {
+        let now = Instant::now();
+        let delivered = self.outbound.lock().unwrap().impair(frame, now);
+        let mut schedule = self.delayed.schedule.lock().unwrap();
+        for (due, frame) in delivered {
+            // frames due right away go out directly, so their errors are returned
+            if due <= now && schedule.is_empty() {
+                try!(self.inner.read().unwrap().send_frame(&frame));
+            } else {
+                schedule.push(due, frame);
+                self.delayed.changed.notify_all();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ImpairedConnection {
+    fn drop(&mut self) {
+        self.delayed.schedule.lock().unwrap().closed = true;
+        self.delayed.changed.notify_all();
+    }
+}
+
+impl MavConnection for ImpairedConnection {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let header = self.link.next_header(&mut *sequence);
+
+        self.send_impaired(&MavFrame {
+            header: header,
+            version: self.link.protocol_version,
+            msg: data.clone(),
+            received: None,
+        })
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        self.send_impaired(frame)
+    }
+
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+        self.link.system_id = system_id;
+        self.link.component_id = component_id;
+        self.inner.write().unwrap().set_source_ids(system_id, component_id);
+    }
+
+    fn get_source_ids(&self) -> (u8, u8) {
+        (self.link.system_id, self.link.component_id)
+    }
+
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.link.protocol_version = version;
+        self.inner.write().unwrap().set_protocol_version(version);
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.link.protocol_version
+    }
+
+    fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.inner.write().unwrap().setup_signing(signing);
+    }
+
+    fn set_error_handler(&mut self, handler: Option<ErrorHandler>) {
+        self.inner.write().unwrap().set_error_handler(handler);
+    }
+
+    fn read_error_counts(&self) -> ReadErrorCounts {
+        self.inner.read().unwrap().read_error_counts()
+    }
+
+    /// Statistics of the wrapped connection, which sees the frames before they are
+    /// impaired on the way in and after on the way out
+    fn link_stats(&self) -> LinkStats {
+        self.inner.read().unwrap().link_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::PING_DATA;
+    use mock::channel_pair;
+
+    const FRAMES: usize = 100;
+
+    fn ping() -> MavMessage {
+        MavMessage::PING(PING_DATA::default())
+    }
+
+    /// Sequence numbers received at the far end of a pair, until it goes quiet
+    fn received(far: &MavConnection) -> Vec<u8> {
+        let mut sequences = Vec::new();
+        while let Ok(frame) = far.recv_timeout(Duration::from_millis(200)) {
+            sequences.push(frame.header.sequence);
+        }
+        sequences
+    }
+
+    /// Send `FRAMES` pings through an impaired connection, returning the sequence
+    /// numbers that arrived and the impairment statistics
+    fn run(seed: u64, impairment: Impairment) -> (Vec<u8>, ImpairmentStats) {
+        let (near, far) = channel_pair();
+        let mut conn = ImpairedConnection::new(Box::new(near), seed);
+        conn.set_outbound(impairment);
+        for _ in 0..FRAMES {
+            conn.send(&ping()).unwrap();
+        }
+        (received(&far), conn.outbound_stats())
+    }
+
+    #[test]
+    fn same_seed_impairs_the_same_way() {
+        let impairment = Impairment {
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(50),
+            ..Impairment::default()
+        };
+        let (sequences, stats) = run(42, impairment);
+        assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0);
+        assert_eq!(sequences.len() as u64, FRAMES as u64 - stats.lost + stats.duplicated);
+        assert_eq!(run(42, impairment), (sequences, stats));
+    }
+
+    #[test]
+    fn loss_drops_every_frame() {
+        let (sequences, stats) = run(1, Impairment { loss: 1.0, ..Impairment::default() });
+        assert!(sequences.is_empty());
+        assert_eq!(stats, ImpairmentStats { lost: FRAMES as u64, ..ImpairmentStats::default() });
+    }
+
+    #[test]
+    fn duplicate_delivers_every_frame_twice() {
+        let (sequences, stats) = run(1, Impairment { duplicate: 1.0, ..Impairment::default() });
+        let expected: Vec<u8> = (0..FRAMES).flat_map(|i| vec![i as u8, i as u8]).collect();
+        assert_eq!(sequences, expected);
+        assert_eq!(stats, ImpairmentStats { duplicated: FRAMES as u64, ..ImpairmentStats::default() });
+    }
+
+    #[test]
+    fn reorder_lets_later_frames_overtake() {
+        let (near, far) = channel_pair();
+        let mut conn = ImpairedConnection::new(Box::new(near), 1);
+        conn.set_outbound(Impairment { reorder: 1.0, ..Impairment::default() });
+        conn.send(&ping()).unwrap();
+        conn.set_outbound(Impairment::default());
+        conn.send(&ping()).unwrap();
+        conn.send(&ping()).unwrap();
+        assert_eq!(received(&far), vec![1, 2, 0]);
+        assert_eq!(conn.outbound_stats(), ImpairmentStats { reordered: 1, ..ImpairmentStats::default() });
+    }
+}
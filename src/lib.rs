@@ -6,6 +6,19 @@ extern crate byteorder;
 extern crate crc16;
 extern crate serial;
 extern crate range_check;
+extern crate sha2;
+extern crate bytes;
+extern crate net2;
+#[cfg(unix)]
+extern crate libc;
+
+#[cfg(feature = "async")]
+#[macro_use]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate tokio_serial;
 
 #[macro_use]
 extern crate serde_derive;
@@ -13,12 +26,58 @@ extern crate serde;
 extern crate serde_json;
 
 use std::io;
+use std::time::{Instant, SystemTime};
 use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
 use std::io::prelude::*;
 
 
+#[macro_use]
 mod connection;
-pub use connection::{ MavConnection, Tcp, Udp, Serial, connect };
+pub use connection::{ MavConnection, Connection, ConnectionBuilder, ErrorHandler, Tcp, TcpServer, Udp, UdpPeer, Serial, SerialConfig, connect };
+pub use connection::{ serial_candidates, DETECT_BAUD_RATES, DEFAULT_DETECT_TIMEOUT };
+#[cfg(unix)]
+pub use connection::{ Unix, UnixDgram, Pty };
+
+mod reconnect;
+pub use reconnect::{ ReconnectingConnection, LinkEvent, EventHandler, Opener };
+
+mod tlog;
+pub use tlog::{ TlogFile, TlogWriter };
+
+mod schedule;
+
+mod mock;
+pub use mock::{ MockConnection, Recorder, Responder, Response, channel_pair };
+
+mod router;
+pub use router::{ Router, RouterHandle, Route };
+
+mod split;
+pub use split::{ MavSender, MavReceiver, split };
+
+mod impair;
+pub use impair::{ ImpairedConnection, Impairment, ImpairmentStats };
+
+mod throttle;
+pub use throttle::{ ThrottledConnection, Priority, DropPolicy };
+
+mod signing;
+pub use signing::{ SigningConfig, SigningData };
+use signing::SIGNATURE_LEN;
+
+mod error;
+pub use error::{ MessageReadError, ReadErrorCounts, RecvTimeoutError };
+
+mod decoder;
+pub use decoder::FrameDecoder;
+
+mod stats;
+pub use stats::{ LinkStats, StreamStats, QueueStats };
+
+#[cfg(feature = "async")]
+mod async_connection;
+#[cfg(feature = "async")]
+pub use async_connection::{ AsyncMavConnection, MavlinkCodec, AsyncStream, AsyncTcp, AsyncSerial, AsyncUdp, connect_async };
 
 /// The MAVLink common message set
 #[allow(non_camel_case_types)]
@@ -30,6 +89,17 @@ pub mod common {
 use common::MavMessage;
 
 const MAV_STX: u8 = 0xFE;
+const MAV_STX_V2: u8 = 0xFD;
+
+/// Incompatibility flag marking a MAVLink 2 frame as signed
+const MAVLINK_IFLAG_SIGNED: u8 = 0x01;
+
+/// Version of the MAVLink wire protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MavlinkVersion {
+    V1,
+    V2,
+}
 
 /// Metadata from a MAVLink packet header
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -39,59 +109,582 @@ pub struct Header {
     pub component_id: u8,
 }
 
+/// A received MAVLink message together with its packet header
+#[derive(Debug, Clone, PartialEq)]
+pub struct MavFrame {
+    pub header: Header,
+    pub version: MavlinkVersion,
+    pub msg: MavMessage,
+    /// When the frame arrived, if it was received from a connection
+    pub received: Option<ReceiveTime>,
+}
+
+/// Time at which a frame was received.
+///
+/// The monotonic time is suited to measuring intervals and latency, the wall clock time
+/// to logging and aligning recordings from different machines.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReceiveTime {
+    pub monotonic: Instant,
+    pub wall_clock: SystemTime,
+}
+
+impl ReceiveTime {
+    pub fn now() -> ReceiveTime {
+        ReceiveTime {
+            monotonic: Instant::now(),
+            wall_clock: SystemTime::now(),
+        }
+    }
+}
+
+/// A MAVLink frame kept as raw bytes.
+///
+/// Raw frames can carry messages outside the compiled dialect. They are written back
+/// out byte for byte by `write_raw`, including the original checksum and signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub header: Header,
+    pub version: MavlinkVersion,
+    pub incompat_flags: u8,
+    pub compat_flags: u8,
+    pub msgid: u32,
+    pub payload: Vec<u8>,
+    pub checksum: u16,
+    pub signature: Option<[u8; SIGNATURE_LEN]>,
+}
+
+impl RawFrame {
+    /// Whether the message id is part of the compiled dialect
+    pub fn is_known(&self) -> bool {
+        MavMessage::payload_len(self.msgid).is_some()
+    }
+
+    /// Decode the payload, checking the CRC of the frame first
+    pub fn decode(&self) -> Result<MavMessage, MessageReadError> {
+        try!(self.check_crc());
+        self.parse()
+    }
+
+    /// Decode into a `MavFrame`, checking the CRC and, with signing data, the signature
+    pub fn to_frame(&self, signing: Option<&SigningData>) -> Result<MavFrame, MessageReadError> {
+        try!(self.check_crc());
+        try!(self.check_signature(signing));
+        Ok(MavFrame {
+            header: self.header,
+            version: self.version,
+            msg: try!(self.parse()),
+            received: None,
+        })
+    }
+
+    /// Header bytes following the start byte, as covered by the CRC
+    fn header_bytes(&self) -> Vec<u8> {
+        let h = self.header;
+        match self.version {
+            MavlinkVersion::V1 => vec![
+                self.payload.len() as u8,
+                h.sequence,
+                h.system_id,
+                h.component_id,
+                self.msgid as u8,
+            ],
+            MavlinkVersion::V2 => vec![
+                self.payload.len() as u8,
+                self.incompat_flags,
+                self.compat_flags,
+                h.sequence,
+                h.system_id,
+                h.component_id,
+                (self.msgid & 0xFF) as u8,
+                ((self.msgid >> 8) & 0xFF) as u8,
+                ((self.msgid >> 16) & 0xFF) as u8,
+            ],
+        }
+    }
+
+    /// Length of the frame on the wire
+    fn wire_len(&self) -> usize {
+        let signature_len = if self.signature.is_some() { SIGNATURE_LEN } else { 0 };
+        1 + self.header_bytes().len() + self.payload.len() + 2 + signature_len
+    }
+
+    fn stx(&self) -> u8 {
+        match self.version {
+            MavlinkVersion::V1 => MAV_STX,
+            MavlinkVersion::V2 => MAV_STX_V2,
+        }
+    }
+
+    fn check_crc(&self) -> Result<(), MessageReadError> {
+        if !self.is_known() {
+            return Err(MessageReadError::UnknownMessage { msgid: self.msgid });
+        }
+        let mut crc_calc = crc16::State::<crc16::MCRF4XX>::new();
+        crc_calc.update(&self.header_bytes());
+        crc_calc.update(&self.payload);
+        crc_calc.update(&[MavMessage::extra_crc(self.msgid)]);
+        if crc_calc.get() != self.checksum {
+            return Err(MessageReadError::BadCrc {
+                msgid: self.msgid,
+                expected: crc_calc.get(),
+                actual: self.checksum,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_signature(&self, signing: Option<&SigningData>) -> Result<(), MessageReadError> {
+        let signing = match signing {
+            Some(signing) => signing,
+            None => return Ok(()),
+        };
+        let accepted = match self.signature {
+            Some(ref signature) => {
+                let mut frame = vec![self.stx()];
+                frame.extend_from_slice(&self.header_bytes());
+                frame.extend_from_slice(&self.payload);
+                try!(frame.write_u16::<LittleEndian>(self.checksum));
+                signing.verify(&frame, signature, self.header.system_id, self.header.component_id)
+            }
+            None => signing.config().allow_unsigned(),
+        };
+        if !accepted {
+            return Err(MessageReadError::SignatureRejected { msgid: self.msgid });
+        }
+        Ok(())
+    }
+
+    fn parse(&self) -> Result<MavMessage, MessageReadError> {
+        let expected_len = match MavMessage::payload_len(self.msgid) {
+            Some(expected_len) => expected_len,
+            None => return Err(MessageReadError::UnknownMessage { msgid: self.msgid }),
+        };
+        let len = self.payload.len();
+
+        // Truncated MAVLink 2 payloads are restored by zero-extending them
+        let mut payload_buf = [0; 255];
+        payload_buf[..len].copy_from_slice(&self.payload);
+        if self.version == MavlinkVersion::V1 && len < expected_len {
+            return Err(MessageReadError::PayloadTooShort {
+                msgid: self.msgid,
+                len: len,
+                expected: expected_len,
+            });
+        }
+
+        match MavMessage::parse(self.msgid, &payload_buf) {
+            Some(msg) => Ok(msg),
+            None => Err(MessageReadError::UnknownMessage { msgid: self.msgid }),
+        }
+    }
+}
+
 /// Read a MAVLink message from a Read stream.
+///
+/// Both MAVLink 1 and MAVLink 2 frames are accepted. Truncated MAVLink 2
+/// payloads are zero-extended before parsing, and the signature trailer of
+/// signed frames is consumed but not verified. Frames that cannot be decoded
+/// are skipped.
 pub fn read<R: Read>(r: &mut R) -> io::Result<(Header, MavMessage)> {
+    read_signed(r, None)
+}
+
+/// Read a MAVLink message from a Read stream, checking message signatures.
+///
+/// With signing data, signed frames are only accepted if their signature and timestamp
+/// are valid, and unsigned frames only if the signing configuration allows them. Without
+/// signing data this behaves like `read`.
+pub fn read_signed<R: Read>(r: &mut R, signing: Option<&SigningData>) -> io::Result<(Header, MavMessage)> {
+    read_lenient(r, signing, |_| ()).map(|frame| (frame.header, frame.msg))
+}
+
+/// Read a MAVLink frame from a Read stream, skipping frames that cannot be decoded.
+///
+/// Every skipped frame is passed to `on_error`. Only I/O errors and the end of the
+/// stream are returned.
+pub fn read_lenient<R, F>(r: &mut R, signing: Option<&SigningData>, mut on_error: F) -> io::Result<MavFrame>
+where
+    R: Read,
+    F: FnMut(&MessageReadError),
+{
     loop {
-        if try!(r.read_u8()) != MAV_STX {
-            continue;
+        match read_frame(r, signing) {
+            Ok(frame) => return Ok(frame),
+            Err(e @ MessageReadError::Io(_)) | Err(e @ MessageReadError::Eof) => return Err(e.into()),
+            Err(e) => on_error(&e),
         }
-        let len    =  try!(r.read_u8()) as usize;
-        let seq    =  try!(r.read_u8());
-        let sysid  =  try!(r.read_u8());
-        let compid =  try!(r.read_u8());
-        let msgid  =  try!(r.read_u8());
-        
-        let mut payload_buf = [0; 255];
-        let payload = &mut payload_buf[..len];
-        try!(r.read_exact(payload));
-        
-        let crc = try!(r.read_u16::<LittleEndian>());
+    }
+}
 
-        let mut crc_calc = crc16::State::<crc16::MCRF4XX>::new();
-        crc_calc.update(&[len as u8, seq, sysid, compid, msgid]);
-        crc_calc.update(payload);
-        crc_calc.update(&[MavMessage::extra_crc(msgid)]);
-        if crc_calc.get() != crc {
-            continue;
-        }
-        if let Some(msg) = MavMessage::parse(msgid, payload) {
-            return Ok((Header { sequence: seq, system_id: sysid, component_id: compid }, msg));
+/// Read a MAVLink frame from a Read stream, keeping the header and protocol version.
+///
+/// Bytes before the next start byte are skipped, but the first frame found is returned
+/// as an error if it cannot be decoded. Signatures are checked as in `read_signed`.
+pub fn read_frame<R: Read>(r: &mut R, signing: Option<&SigningData>) -> Result<MavFrame, MessageReadError> {
+    try!(read_raw_frame(r)).to_frame(signing)
+}
+
+/// Read a MAVLink frame from a Read stream without decoding it.
+///
+/// The CRC is checked for messages of the compiled dialect; frames with any other
+/// message id are passed through unchecked.
+pub fn read_raw<R: Read>(r: &mut R) -> Result<RawFrame, MessageReadError> {
+    let frame = try!(read_raw_frame(r));
+    if frame.is_known() {
+        try!(frame.check_crc());
+    }
+    Ok(frame)
+}
+
+/// Read the next frame following a start byte, without checking it
+fn read_raw_frame<R: Read>(r: &mut R) -> Result<RawFrame, MessageReadError> {
+    loop {
+        match try!(r.read_u8()) {
+            MAV_STX => return read_v1_raw(r),
+            MAV_STX_V2 => return read_v2_raw(r),
+            _ => continue,
         }
     }
 }
 
+/// Read the remainder of a MAVLink 1 frame after the start byte.
+fn read_v1_raw<R: Read>(r: &mut R) -> Result<RawFrame, MessageReadError> {
+    let len    =  try!(r.read_u8()) as usize;
+    let seq    =  try!(r.read_u8());
+    let sysid  =  try!(r.read_u8());
+    let compid =  try!(r.read_u8());
+    let msgid  =  try!(r.read_u8());
+
+    let mut payload = vec![0; len];
+    try!(r.read_exact(&mut payload));
+
+    let crc = try!(r.read_u16::<LittleEndian>());
+
+    Ok(RawFrame {
+        header: Header { sequence: seq, system_id: sysid, component_id: compid },
+        version: MavlinkVersion::V1,
+        incompat_flags: 0,
+        compat_flags: 0,
+        msgid: msgid as u32,
+        payload: payload,
+        checksum: crc,
+        signature: None,
+    })
+}
+
+/// Read the remainder of a MAVLink 2 frame after the start byte.
+fn read_v2_raw<R: Read>(r: &mut R) -> Result<RawFrame, MessageReadError> {
+    let mut hdr = [0; 9];
+    try!(r.read_exact(&mut hdr));
+    let len = hdr[0] as usize;
+    let incompat_flags = hdr[1];
+    let msgid = hdr[6] as u32 | (hdr[7] as u32) << 8 | (hdr[8] as u32) << 16;
+
+    let mut payload = vec![0; len];
+    try!(r.read_exact(&mut payload));
+
+    let crc = try!(r.read_u16::<LittleEndian>());
+
+    let signature = if incompat_flags & MAVLINK_IFLAG_SIGNED != 0 {
+        let mut signature = [0; SIGNATURE_LEN];
+        try!(r.read_exact(&mut signature));
+        Some(signature)
+    } else {
+        None
+    };
+
+    Ok(RawFrame {
+        header: Header { sequence: hdr[3], system_id: hdr[4], component_id: hdr[5] },
+        version: MavlinkVersion::V2,
+        incompat_flags: incompat_flags,
+        compat_flags: hdr[2],
+        msgid: msgid,
+        payload: payload,
+        checksum: crc,
+        signature: signature,
+    })
+}
+
+/// Write a raw MAVLink frame to a Write stream exactly as it was received.
+pub fn write_raw<W: Write>(w: &mut W, frame: &RawFrame) -> io::Result<()> {
+    if frame.version == MavlinkVersion::V1 && frame.msgid > 0xFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message id does not fit in a MAVLink 1 frame",
+        ));
+    }
+    let mut buf = vec![frame.stx()];
+    buf.extend_from_slice(&frame.header_bytes());
+    buf.extend_from_slice(&frame.payload);
+    try!(buf.write_u16::<LittleEndian>(frame.checksum));
+    if let Some(ref signature) = frame.signature {
+        buf.extend_from_slice(signature);
+    }
+    w.write_all(&buf)
+}
+
 /// Write a MAVLink message to a Write stream.
+///
+/// The message is framed as MAVLink 1; use `write_versioned` to pick the
+/// protocol version.
 pub fn write<W: Write>(w: &mut W, header: Header, data: &MavMessage) -> io::Result<()> {
+    write_v1(w, header, data)
+}
+
+/// Write a MAVLink message to a Write stream using the given protocol version.
+pub fn write_versioned<W: Write>(
+    w: &mut W,
+    version: MavlinkVersion,
+    header: Header,
+    data: &MavMessage,
+) -> io::Result<()> {
+    write_signed(w, version, header, data, None)
+}
+
+/// Write a MAVLink message to a Write stream, signing it if the signing configuration asks for it.
+///
+/// Only MAVLink 2 frames can carry a signature, so asking to sign a MAVLink 1 frame is an error.
+pub fn write_signed<W: Write>(
+    w: &mut W,
+    version: MavlinkVersion,
+    header: Header,
+    data: &MavMessage,
+    signing: Option<&SigningData>,
+) -> io::Result<()> {
+    let signing = signing.and_then(|s| if s.config().sign_outgoing() { Some(s) } else { None });
+    match version {
+        MavlinkVersion::V1 => {
+            if signing.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "MAVLink 1 frames cannot be signed",
+                ));
+            }
+            write_v1(w, header, data)
+        }
+        MavlinkVersion::V2 => write_v2_frame(w, header, data, signing),
+    }
+}
+
+/// Write a MAVLink 1 frame to a Write stream.
+pub fn write_v1<W: Write>(w: &mut W, header: Header, data: &MavMessage) -> io::Result<()> {
     let msgid = data.message_id();
+    if msgid > 0xFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message id does not fit in a MAVLink 1 frame",
+        ));
+    }
     let payload = data.serialize();
-    
+
     let header = &[
         MAV_STX,
         payload.len() as u8,
         header.sequence,
         header.system_id,
         header.component_id,
-        msgid,
+        msgid as u8,
     ];
-    
+
     let mut crc = crc16::State::<crc16::MCRF4XX>::new();
     crc.update(&header[1..]);
     crc.update(&payload[..]);
     crc.update(&[MavMessage::extra_crc(msgid)]);
-    
+
     try!(w.write_all(header));
     try!(w.write_all(&payload[..]));
     try!(w.write_u16::<LittleEndian>(crc.get()));
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Write an unsigned MAVLink 2 frame to a Write stream.
+///
+/// Trailing zero bytes of the payload are truncated as required by the
+/// MAVLink 2 specification, always keeping at least one byte.
+pub fn write_v2<W: Write>(w: &mut W, header: Header, data: &MavMessage) -> io::Result<()> {
+    write_v2_frame(w, header, data, None)
+}
+
+fn write_v2_frame<W: Write>(
+    w: &mut W,
+    header: Header,
+    data: &MavMessage,
+    signing: Option<&SigningData>,
+) -> io::Result<()> {
+    let msgid = data.message_id();
+    let payload = data.serialize();
+    let len = truncated_len(&payload);
+
+    let incompat_flags = if signing.is_some() { MAVLINK_IFLAG_SIGNED } else { 0 };
+
+    let mut frame = Vec::with_capacity(10 + len + 2 + SIGNATURE_LEN);
+    frame.extend_from_slice(&[
+        MAV_STX_V2,
+        len as u8,
+        incompat_flags,
+        0, // compat_flags
+        header.sequence,
+        header.system_id,
+        header.component_id,
+        (msgid & 0xFF) as u8,
+        ((msgid >> 8) & 0xFF) as u8,
+        ((msgid >> 16) & 0xFF) as u8,
+    ]);
+    frame.extend_from_slice(&payload[..len]);
+
+    let mut crc = crc16::State::<crc16::MCRF4XX>::new();
+    crc.update(&frame[1..]);
+    crc.update(&[MavMessage::extra_crc(msgid)]);
+    try!(frame.write_u16::<LittleEndian>(crc.get()));
+
+    if let Some(signing) = signing {
+        let signature = signing.sign(&frame);
+        frame.extend_from_slice(&signature);
+    }
+
+    w.write_all(&frame)
+}
+
+/// Length of the unsigned frame of a message on the wire
+fn unsigned_frame_len(version: MavlinkVersion, data: &MavMessage) -> usize {
+    let payload = data.serialize();
+    match version {
+        // stx, header, payload, crc
+        MavlinkVersion::V1 => 1 + 5 + payload.len() + 2,
+        MavlinkVersion::V2 => 1 + 9 + truncated_len(&payload) + 2,
+    }
+}
+
+/// Length of a MAVLink 2 payload with its trailing zeros removed
+fn truncated_len(payload: &[u8]) -> usize {
+    let mut len = payload.len();
+    while len > 1 && payload[len - 1] == 0 {
+        len -= 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::HEARTBEAT_DATA;
+
+    const HEADER: Header = Header {
+        sequence: 7,
+        system_id: 1,
+        component_id: 2,
+    };
+
+    fn heartbeat() -> MavMessage {
+        MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())
+    }
+
+    /// COMMAND_LONG to `target_system`, whose last payload byte is zero
+    fn command(target_system: u8) -> MavMessage {
+        let mut payload = [0; 33];
+        payload[0] = 0x3f;
+        payload[30] = target_system;
+        MavMessage::parse(76, &payload).unwrap()
+    }
+
+    fn encode(version: MavlinkVersion, msg: &MavMessage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_versioned(&mut buf, version, HEADER, msg).unwrap();
+        buf
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let buf = encode(MavlinkVersion::V1, &command(3));
+        assert_eq!(buf[0], MAV_STX);
+        // stx, header, full payload, crc
+        assert_eq!(buf.len(), 1 + 5 + 33 + 2);
+        let frame = read_frame(&mut &buf[..], None).unwrap();
+        assert_eq!((frame.header, frame.version, frame.msg), (HEADER, MavlinkVersion::V1, command(3)));
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let buf = encode(MavlinkVersion::V2, &command(3));
+        assert_eq!(buf[0], MAV_STX_V2);
+        let frame = read_frame(&mut &buf[..], None).unwrap();
+        assert_eq!((frame.header, frame.version, frame.msg), (HEADER, MavlinkVersion::V2, command(3)));
+    }
+
+    #[test]
+    fn v2_truncates_trailing_zeros() {
+        // the bytes after the target system are zero
+        let buf = encode(MavlinkVersion::V2, &command(3));
+        assert_eq!(buf[1], 31);
+        assert_eq!(buf.len(), 1 + 9 + 31 + 2);
+
+        // an all-zero payload keeps one byte
+        let buf = encode(MavlinkVersion::V2, &heartbeat());
+        assert_eq!(buf[1], 1);
+        assert_eq!(buf.len(), 1 + 9 + 1 + 2);
+    }
+
+    #[test]
+    fn frame_len_matches_written_frame() {
+        for &version in [MavlinkVersion::V1, MavlinkVersion::V2].iter() {
+            for msg in [heartbeat(), command(3)].iter() {
+                assert_eq!(unsigned_frame_len(version, msg), encode(version, msg).len());
+            }
+        }
+    }
+
+    #[test]
+    fn v2_zero_extends_truncated_payloads() {
+        let buf = encode(MavlinkVersion::V2, &command(3));
+        let raw = read_raw(&mut &buf[..]).unwrap();
+        assert_eq!(raw.payload.len(), 31);
+        assert_eq!(raw.decode().unwrap(), command(3));
+        assert_eq!(read(&mut &encode(MavlinkVersion::V2, &heartbeat())[..]).unwrap(), (HEADER, heartbeat()));
+    }
+
+    #[test]
+    fn v1_rejects_message_ids_above_255() {
+        let raw = RawFrame {
+            header: HEADER,
+            version: MavlinkVersion::V1,
+            incompat_flags: 0,
+            compat_flags: 0,
+            msgid: 256,
+            payload: vec![1, 2, 3],
+            checksum: 0,
+            signature: None,
+        };
+        let mut buf = Vec::new();
+        let e = write_raw(&mut buf, &raw).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        let raw = RawFrame {
+            version: MavlinkVersion::V2,
+            ..raw
+        };
+        write_raw(&mut buf, &raw).unwrap();
+        assert_eq!(read_raw(&mut &buf[..]).unwrap(), raw);
+    }
+
+    #[test]
+    fn signing_adds_signature_to_frame() {
+        let signing = SigningData::from_config(SigningConfig::new([7; 32], 1, true, false));
+        let mut unsigned = Vec::new();
+        write_signed(&mut unsigned, MavlinkVersion::V2, HEADER, &command(3), None).unwrap();
+        let mut signed = Vec::new();
+        write_signed(&mut signed, MavlinkVersion::V2, HEADER, &command(3), Some(&signing)).unwrap();
+
+        assert_eq!(signed.len(), unsigned.len() + SIGNATURE_LEN);
+        assert_eq!(signed[2] & MAVLINK_IFLAG_SIGNED, MAVLINK_IFLAG_SIGNED);
+        assert_eq!(unsigned[2] & MAVLINK_IFLAG_SIGNED, 0);
+        let frame = read_frame(&mut &signed[..], Some(&signing)).unwrap();
+        assert_eq!(frame.msg, command(3));
+
+        // MAVLink 1 has no room for a signature
+        let e = write_signed(&mut Vec::new(), MavlinkVersion::V1, HEADER, &command(3), Some(&signing)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
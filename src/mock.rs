@@ -0,0 +1,383 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use common::MavMessage;
+use connection::{deadline_passed, LinkConfig, MavConnection};
+use error::RecvTimeoutError;
+use schedule::Schedule;
+use {unsigned_frame_len, Header, MavFrame, ReceiveTime};
+
+/// System and component id that responders reply from by default
+const DEFAULT_REMOTE_IDS: (u8, u8) = (1, 1);
+
+/// Message scheduled by a responder, delivered `delay` after the message it answers
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub delay: Duration,
+    pub msg: MavMessage,
+}
+
+impl Response {
+    /// Deliver `msg` right away
+    pub fn now(msg: MavMessage) -> Response {
+        Response::after(Duration::from_secs(0), msg)
+    }
+
+    /// Deliver `msg` after `delay`
+    pub fn after(delay: Duration, msg: MavMessage) -> Response {
+        Response {
+            delay: delay,
+            msg: msg,
+        }
+    }
+}
+
+/// Callback invoked for every frame sent on a `MockConnection`, returning the replies
+pub type Responder = Box<Fn(&MavFrame) -> Vec<Response> + Send + Sync>;
+
+/// Frames waiting to be received by a `MockConnection`
+struct Inbox {
+    /// Closed when the sending end is dropped
+    state: Mutex<Schedule>,
+    changed: Condvar,
+}
+
+impl Inbox {
+    fn new() -> Arc<Inbox> {
+        Arc::new(Inbox {
+            state: Mutex::new(Schedule::new()),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn push(&self, due: Instant, frame: MavFrame) {
+        self.state.lock().unwrap().push(due, frame);
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Take the next due frame, waiting for it until `deadline` if there is one
+    fn pop(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if let Some(frame) = state.pop_due(now) {
+                return Ok(frame);
+            }
+            let mut wake = deadline;
+            match state.next_due() {
+                Some(due) => wake = Some(deadline.map_or(due, |deadline| ::std::cmp::min(deadline, due))),
+                None if state.closed => {
+                    return Err(RecvTimeoutError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "other end of the mock connection was dropped",
+                    )))
+                }
+                None => (),
+            }
+            if deadline_passed(deadline) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = match wake {
+                Some(wake) if wake > now => self.changed.wait_timeout(state, wake - now).unwrap().0,
+                Some(_) => state,
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+/// Record of the frames sent on a `MockConnection`.
+///
+/// Clones share the same record, so one can be kept to inspect a connection that was
+/// handed over to the code under test.
+#[derive(Clone)]
+pub struct Recorder {
+    frames: Arc<Mutex<Vec<MavFrame>>>,
+}
+
+impl Recorder {
+    fn new() -> Recorder {
+        Recorder {
+            frames: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn record(&self, frame: &MavFrame) {
+        self.frames.lock().unwrap().push(frame.clone());
+    }
+
+    /// Every frame sent so far, in order
+    pub fn frames(&self) -> Vec<MavFrame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// Every message sent so far, in order
+    pub fn messages(&self) -> Vec<MavMessage> {
+        self.frames.lock().unwrap().iter().map(|frame| frame.msg.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    pub fn clear(&self) {
+        self.frames.lock().unwrap().clear();
+    }
+}
+
+/// In-memory MAVLink connection for testing.
+///
+/// Frames are passed between the two ends of a `channel_pair` as they are, without being
+/// encoded, so signing is not applied. A standalone connection from `new` plays the other
+/// end itself: messages pushed on it are received, and responders answer the messages
+/// sent on it.
+///
+/// ```ignore
+/// // answer COMMAND_LONG (id 76) with COMMAND_ACK after 50ms
+/// let mut vehicle = MockConnection::new();
+/// vehicle.reply_to(76, Duration::from_millis(50), |_| MavMessage::COMMAND_ACK(ack.clone()));
+/// let sent = vehicle.recorder();
+/// ```
+pub struct MockConnection {
+    inbox: Arc<Inbox>,
+    /// Inbox of the other end of a pair
+    peer: Option<Arc<Inbox>>,
+    recorder: Recorder,
+    responders: Vec<Responder>,
+    remote_ids: (u8, u8),
+    sequence: Mutex<u8>,
+    remote_sequence: Mutex<u8>,
+    link: LinkConfig,
+}
+
+/// Create two mock connections, each receiving what the other sends
+pub fn channel_pair() -> (MockConnection, MockConnection) {
+    let mut a = MockConnection::new();
+    let mut b = MockConnection::new();
+    a.peer = Some(b.inbox.clone());
+    b.peer = Some(a.inbox.clone());
+    (a, b)
+}
+
+impl MockConnection {
+    /// Create a standalone mock connection, with no messages to receive
+    pub fn new() -> MockConnection {
+        MockConnection {
+            inbox: Inbox::new(),
+            peer: None,
+            recorder: Recorder::new(),
+            responders: Vec::new(),
+            remote_ids: DEFAULT_REMOTE_IDS,
+            sequence: Mutex::new(0),
+            remote_sequence: Mutex::new(0),
+            link: LinkConfig::new(),
+        }
+    }
+
+    /// Handle on the record of every frame sent on this connection
+    pub fn recorder(&self) -> Recorder {
+        self.recorder.clone()
+    }
+
+    /// Set the system and component id that pushed messages and replies come from
+    pub fn set_remote_ids(&mut self, system_id: u8, component_id: u8) {
+        self.remote_ids = (system_id, component_id);
+    }
+
+    /// Add a callback answering every frame sent on this connection
+    pub fn add_responder<F>(&mut self, responder: F)
+    where
+        F: Fn(&MavFrame) -> Vec<Response> + Send + Sync + 'static,
+    {
+        self.responders.push(Box::new(responder));
+    }
+
+    /// Answer every message with id `msgid` with the message made by `reply`, after `delay`
+    pub fn reply_to<F>(&mut self, msgid: u32, delay: Duration, reply: F)
+    where
+        F: Fn(&MavFrame) -> MavMessage + Send + Sync + 'static,
+    {
+        self.add_responder(move |frame| {
+            if frame.msg.message_id() == msgid {
+                vec![Response::after(delay, reply(frame))]
+            } else {
+                Vec::new()
+            }
+        });
+    }
+
+    /// Queue a message to be received, sent from the remote ids
+    pub fn push(&self, msg: MavMessage) {
+        self.push_after(Duration::from_secs(0), msg);
+    }
+
+    /// Queue a message to be received after `delay`, sent from the remote ids
+    pub fn push_after(&self, delay: Duration, msg: MavMessage) {
+        let frame = self.remote_frame(msg);
+        self.inbox.push(Instant::now() + delay, frame);
+    }
+
+    /// Queue a frame to be received exactly as given
+    pub fn push_frame(&self, frame: MavFrame) {
+        self.inbox.push(Instant::now(), frame);
+    }
+
+    fn remote_frame(&self, msg: MavMessage) -> MavFrame {
+        let mut sequence = self.remote_sequence.lock().unwrap();
+        let header = Header {
+            sequence: *sequence,
+            system_id: self.remote_ids.0,
+            component_id: self.remote_ids.1,
+        };
+        *sequence = sequence.wrapping_add(1);
+        MavFrame {
+            header: header,
+            version: self.link.protocol_version,
+            msg: msg,
+            received: None,
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut frame = try!(self.inbox.pop(deadline));
+        frame.received = Some(ReceiveTime::now());
+        self.link.stats.record_bytes_received(frame_len(&frame));
+        self.link.stats.record_received(&frame.header);
+        Ok(frame)
+    }
+
+    fn deliver(&self, frame: MavFrame) -> io::Result<()> {
+        self.recorder.record(&frame);
+        self.link.stats.record_sent(frame_len(&frame));
+        let now = Instant::now();
+        for responder in &self.responders {
+            for response in responder(&frame) {
+                let reply = self.remote_frame(response.msg);
+                self.inbox.push(now + response.delay, reply);
+            }
+        }
+        if let Some(ref peer) = self.peer {
+            if Arc::strong_count(peer) == 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "other end of the mock connection was dropped",
+                ));
+            }
+            peer.push(now, frame);
+        }
+        Ok(())
+    }
+}
+
+/// Length the frame would have on the wire
+fn frame_len(frame: &MavFrame) -> usize {
+    unsigned_frame_len(frame.version, &frame.msg)
+}
+
+impl Drop for MockConnection {
+    fn drop(&mut self) {
+        if let Some(ref peer) = self.peer {
+            peer.close();
+        }
+    }
+}
+
+impl MavConnection for MockConnection {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let mut sequence = self.sequence.lock().unwrap();
+
+        let header = self.link.next_header(&mut *sequence);
+        self.deliver(MavFrame {
+            header: header,
+            version: self.link.protocol_version,
+            msg: data.clone(),
+            received: None,
+        })
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let mut frame = frame.clone();
+        frame.received = None;
+        self.deliver(frame)
+    }
+
+    link_config_methods!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{HEARTBEAT_DATA, PING_DATA};
+
+    fn heartbeat() -> MavMessage {
+        MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())
+    }
+
+    fn ping() -> MavMessage {
+        MavMessage::PING(PING_DATA::default())
+    }
+
+    #[test]
+    fn responder_replies_after_delay() {
+        let mut vehicle = MockConnection::new();
+        vehicle.set_remote_ids(3, 4);
+        vehicle.reply_to(4, Duration::from_millis(100), |_| heartbeat());
+        let sent = Instant::now();
+        vehicle.send(&ping()).unwrap();
+        vehicle.send(&heartbeat()).unwrap();
+
+        match vehicle.recv_timeout(Duration::from_millis(50)) {
+            Err(RecvTimeoutError::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        let reply = vehicle.recv_timeout(Duration::from_millis(500)).unwrap();
+        assert!(sent.elapsed() >= Duration::from_millis(100));
+        assert_eq!(reply.msg, heartbeat());
+        assert_eq!((reply.header.system_id, reply.header.component_id), (3, 4));
+        // only the ping was answered
+        assert!(vehicle.try_recv().is_err());
+    }
+
+    #[test]
+    fn recorder_keeps_sent_frames() {
+        let conn = MockConnection::new();
+        let recorder = conn.recorder();
+        let conn: Box<MavConnection> = Box::new(conn);
+        conn.send(&heartbeat()).unwrap();
+        conn.send(&ping()).unwrap();
+        let mut frame = recorder.frames()[0].clone();
+        frame.header.sequence = 42;
+        conn.send_frame(&frame).unwrap();
+
+        assert_eq!(recorder.messages(), vec![heartbeat(), ping(), heartbeat()]);
+        let sequences: Vec<u8> = recorder.frames().iter().map(|frame| frame.header.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 42]);
+        recorder.clear();
+        assert_eq!(recorder.len(), 0);
+    }
+
+    #[test]
+    fn dropped_end_gives_eof() {
+        let (a, b) = channel_pair();
+        a.send(&heartbeat()).unwrap();
+        drop(a);
+        // frames sent before the drop are still received
+        assert_eq!(b.recv_frame().unwrap().msg, heartbeat());
+        assert_eq!(b.recv_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(b.send(&heartbeat()).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
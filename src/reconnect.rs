@@ -0,0 +1,377 @@
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::MavMessage;
+use connection::{Connection, ErrorHandler, MavConnection, DEFAULT_COMPONENT_ID, DEFAULT_SYSTEM_ID};
+use error::{ReadErrorCounts, RecvTimeoutError};
+use stats::{LinkStats, StatsTracker};
+use {MavFrame, MavlinkVersion, SigningConfig};
+
+/// Delay before the second attempt to open a link
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest delay between attempts to open a link
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Change of state of a reconnecting link
+#[derive(Debug)]
+pub enum LinkEvent {
+    /// The link was opened
+    Connected,
+    /// The link failed and will be re-opened
+    Disconnected(io::Error),
+    /// An attempt to open the link failed; the next one is made after `retry_in`
+    ConnectFailed { error: io::Error, retry_in: Duration },
+}
+
+/// Callback invoked for every connect and disconnect of a reconnecting link
+pub type EventHandler = Box<Fn(&LinkEvent) + Send + Sync>;
+
+/// Opens the underlying connection of a `ReconnectingConnection`
+pub type Opener = Box<Fn() -> io::Result<Connection> + Send + Sync>;
+
+/// Settings applied to every link that is opened
+struct Settings {
+    protocol_version: MavlinkVersion,
+    source_ids: Option<(u8, u8)>,
+    signing: Option<SigningConfig>,
+    error_handler: Option<Arc<ErrorHandler>>,
+    event_handler: Option<Arc<EventHandler>>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Settings {
+    fn apply(&self, conn: &mut Connection) {
+        conn.set_protocol_version(self.protocol_version);
+        if let Some((system_id, component_id)) = self.source_ids {
+            conn.set_source_ids(system_id, component_id);
+        }
+        if self.signing.is_some() {
+            conn.setup_signing(self.signing.clone());
+        }
+        if let Some(ref handler) = self.error_handler {
+            let handler = handler.clone();
+            conn.set_error_handler(Some(Box::new(move |e| handler(e))));
+        }
+    }
+}
+
+struct LinkState {
+    conn: Option<Arc<Connection>>,
+    /// Whether `conn` is believed to work; a failed connection is kept for its statistics
+    alive: bool,
+    /// Whether a background thread is opening the link
+    opening: bool,
+    next_attempt: Instant,
+    backoff: Duration,
+    settings: Settings,
+}
+
+/// State shared with the thread opening the link
+struct Shared {
+    open: Opener,
+    state: Mutex<LinkState>,
+    /// Notified when an attempt to open the link ends
+    attempt_done: Condvar,
+}
+
+impl Shared {
+    /// Make one attempt to open the link, then install it or schedule the next attempt
+    fn attempt(&self) {
+        let result = (self.open)();
+        let mut state = self.state.lock().unwrap();
+        state.opening = false;
+        let event = match result {
+            Ok(mut conn) => {
+                state.settings.apply(&mut conn);
+                state.conn = Some(Arc::new(conn));
+                state.alive = true;
+                state.backoff = state.settings.initial_backoff;
+                LinkEvent::Connected
+            }
+            Err(e) => {
+                let retry_in = state.backoff;
+                state.next_attempt = Instant::now() + retry_in;
+                state.backoff = cmp::min(retry_in * 2, state.settings.max_backoff);
+                LinkEvent::ConnectFailed {
+                    error: e,
+                    retry_in: retry_in,
+                }
+            }
+        };
+        let handler = state.settings.event_handler.clone();
+        self.attempt_done.notify_all();
+        drop(state);
+        // the handler may use the connection, so the lock is released first
+        if let Some(handler) = handler {
+            handler(&event);
+        }
+    }
+}
+
+/// A connection that re-opens its link whenever it fails.
+///
+/// When the link fails, `recv` blocks until it is re-opened and then carries on, while
+/// `send` fails with `NotConnected` as long as the link is down. Attempts to re-open the
+/// link are made on a background thread and spaced by a delay that doubles after every
+/// failure, up to a maximum, so `send`, `try_recv` and `recv_timeout` never wait for a
+/// slow attempt.
+///
+/// The protocol version, source ids, signing configuration and error handler are applied
+/// again to every new link. Statistics and error counts are those of the current link.
+/// The event handler is called without any lock held, so it may use the connection.
+pub struct ReconnectingConnection {
+    shared: Arc<Shared>,
+}
+
+impl ReconnectingConnection {
+    /// Create a connection opened by `open`.
+    ///
+    /// The link is first opened when the connection is used, so a node that is not up yet
+    /// is waited for like one that went down.
+    pub fn new<F>(open: F) -> ReconnectingConnection
+    where
+        F: Fn() -> io::Result<Connection> + Send + Sync + 'static,
+    {
+        ReconnectingConnection {
+            shared: Arc::new(Shared {
+                open: Box::new(open),
+                state: Mutex::new(LinkState {
+                    conn: None,
+                    alive: false,
+                    opening: false,
+                    next_attempt: Instant::now(),
+                    backoff: DEFAULT_INITIAL_BACKOFF,
+                    settings: Settings {
+                        protocol_version: MavlinkVersion::V1,
+                        source_ids: None,
+                        signing: None,
+                        error_handler: None,
+                        event_handler: None,
+                        initial_backoff: DEFAULT_INITIAL_BACKOFF,
+                        max_backoff: DEFAULT_MAX_BACKOFF,
+                    },
+                }),
+                attempt_done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Set the delay after the first failed attempt and the longest delay between attempts
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.settings.initial_backoff = initial;
+        state.settings.max_backoff = max;
+        state.backoff = initial;
+    }
+
+    /// Set the callback invoked on connect and disconnect, or remove it with `None`
+    pub fn set_event_handler(&mut self, handler: Option<EventHandler>) {
+        self.shared.state.lock().unwrap().settings.event_handler = handler.map(Arc::new);
+    }
+
+    /// Whether the link is currently believed to be up
+    pub fn is_connected(&self) -> bool {
+        self.shared.state.lock().unwrap().alive
+    }
+
+    /// Get the current link, starting an attempt to open it if it is down.
+    ///
+    /// Without a deadline, blocks until the link is up. Otherwise `NotConnected` is
+    /// returned if the link is still down at the deadline. Attempts run on a background
+    /// thread, one at a time, so a deadline that has already passed returns at once.
+    fn link(&self, deadline: Option<Instant>) -> io::Result<Arc<Connection>> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.alive {
+                if let Some(ref conn) = state.conn {
+                    return Ok(conn.clone());
+                }
+            }
+
+            let now = Instant::now();
+            if !state.opening && now >= state.next_attempt {
+                state.opening = true;
+                let shared = shared.clone();
+                thread::spawn(move || shared.attempt());
+            }
+
+            // wait for the attempt in progress, or for the next one to be due
+            let mut wake = if state.opening { None } else { Some(state.next_attempt) };
+            if let Some(deadline) = deadline {
+                if now >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "link is down"));
+                }
+                wake = Some(wake.map_or(deadline, |wake| cmp::min(wake, deadline)));
+            }
+            state = match wake {
+                Some(wake) if wake > now => shared.attempt_done.wait_timeout(state, wake - now).unwrap().0,
+                Some(_) => state,
+                None => shared.attempt_done.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Mark `conn` as failed, unless the link was already re-opened since
+    fn failed(&self, conn: &Arc<Connection>, e: io::Error) {
+        let mut state = self.shared.state.lock().unwrap();
+        let current = match state.conn {
+            Some(ref current) => Arc::ptr_eq(current, conn),
+            None => false,
+        };
+        if current && state.alive {
+            state.alive = false;
+            state.next_attempt = Instant::now();
+            let handler = state.settings.event_handler.clone();
+            drop(state);
+            if let Some(handler) = handler {
+                handler(&LinkEvent::Disconnected(e));
+            }
+        }
+    }
+
+    /// Change the settings along with the current link, which is not in use while we hold
+    /// `&mut self`
+    fn with_current<F: FnOnce(&mut Settings, Option<&mut Connection>)>(&mut self, f: F) {
+        let mut guard = self.shared.state.lock().unwrap();
+        let state = &mut *guard;
+        f(&mut state.settings, state.conn.as_mut().and_then(Arc::get_mut));
+    }
+}
+
+/// Whether an error means the link itself failed, as opposed to a bad message or a timeout
+pub fn is_link_failure(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::Interrupted => false,
+        _ => true,
+    }
+}
+
+impl MavConnection for ReconnectingConnection {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        loop {
+            let conn = try!(self.link(None));
+            match conn.recv_frame() {
+                Ok(frame) => return Ok(frame),
+                Err(e) => {
+                    if !is_link_failure(&e) {
+                        return Err(e);
+                    }
+                    self.failed(&conn, e);
+                }
+            }
+        }
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let conn = match self.link(Some(deadline)) {
+                Ok(conn) => conn,
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected => return Err(RecvTimeoutError::Timeout),
+                Err(e) => return Err(e.into()),
+            };
+            let now = Instant::now();
+            let remaining = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+            match conn.recv_timeout(remaining) {
+                Err(RecvTimeoutError::Io(ref e)) if is_link_failure(e) => {
+                    self.failed(&conn, io::Error::new(e.kind(), e.to_string()));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let conn = try!(self.link(Some(Instant::now())));
+        conn.send(data).map_err(|e| {
+            if is_link_failure(&e) {
+                self.failed(&conn, io::Error::new(e.kind(), e.to_string()));
+            }
+            e
+        })
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let conn = try!(self.link(Some(Instant::now())));
+        conn.send_frame(frame).map_err(|e| {
+            if is_link_failure(&e) {
+                self.failed(&conn, io::Error::new(e.kind(), e.to_string()));
+            }
+            e
+        })
+    }
+
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+        self.with_current(|settings, conn| {
+            settings.source_ids = Some((system_id, component_id));
+            if let Some(conn) = conn {
+                conn.set_source_ids(system_id, component_id);
+            }
+        });
+    }
+
+    fn get_source_ids(&self) -> (u8, u8) {
+        let state = self.shared.state.lock().unwrap();
+        match (state.settings.source_ids, &state.conn) {
+            (Some(ids), _) => ids,
+            (None, &Some(ref conn)) => conn.get_source_ids(),
+            (None, &None) => (DEFAULT_SYSTEM_ID, DEFAULT_COMPONENT_ID),
+        }
+    }
+
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.with_current(|settings, conn| {
+            settings.protocol_version = version;
+            if let Some(conn) = conn {
+                conn.set_protocol_version(version);
+            }
+        });
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.shared.state.lock().unwrap().settings.protocol_version
+    }
+
+    fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.with_current(|settings, conn| {
+            settings.signing = signing.clone();
+            if let Some(conn) = conn {
+                conn.setup_signing(signing);
+            }
+        });
+    }
+
+    fn set_error_handler(&mut self, handler: Option<ErrorHandler>) {
+        self.with_current(|settings, conn| {
+            settings.error_handler = handler.map(Arc::new);
+            if let Some(conn) = conn {
+                let handler = settings.error_handler.clone();
+                conn.set_error_handler(handler.map(|handler| Box::new(move |e: &_| handler(e)) as ErrorHandler));
+            }
+        });
+    }
+
+    fn read_error_counts(&self) -> ReadErrorCounts {
+        match self.shared.state.lock().unwrap().conn {
+            Some(ref conn) => conn.read_error_counts(),
+            None => ReadErrorCounts::default(),
+        }
+    }
+
+    fn link_stats(&self) -> LinkStats {
+        match self.shared.state.lock().unwrap().conn {
+            Some(ref conn) => conn.link_stats(),
+            None => StatsTracker::new().snapshot(ReadErrorCounts::default()),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use connection::Connection;
+use mock::{channel_pair, MockConnection};
+use reconnect::is_link_failure;
+use {Header, MavFrame};
+
+/// How long a component stays bound to a link it is no longer heard on, once it is heard
+/// on another one
+const DEFAULT_ROUTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a forwarded frame is remembered to recognize copies of it that come back
+/// around a loop or over a redundant link
+const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_millis(250);
+
+/// A system and component heard on one of the links of a `Router`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub system_id: u8,
+    pub component_id: u8,
+    /// Index of the link, in the order links were added
+    pub link: usize,
+    pub last_seen: Instant,
+}
+
+/// System id, component id, sequence number and message id of a frame
+type FrameId = (u8, u8, u8, u32);
+
+struct RouteTable {
+    /// Routes by system id, component id and link
+    routes: HashMap<(u8, u8, usize), Route>,
+    timeout: Duration,
+    /// Frames forwarded within the dedup window, oldest first
+    recent: VecDeque<(FrameId, Instant)>,
+    /// Latest time each frame in `recent` was forwarded
+    recent_ids: HashMap<FrameId, Instant>,
+    dedup_window: Duration,
+}
+
+impl RouteTable {
+    fn new() -> RouteTable {
+        RouteTable {
+            routes: HashMap::new(),
+            timeout: DEFAULT_ROUTE_TIMEOUT,
+            recent: VecDeque::new(),
+            recent_ids: HashMap::new(),
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+        }
+    }
+
+    /// Note that a frame with message id `msgid` arrived on `link`.
+    ///
+    /// Returns `false` if a frame with the same sender, sequence number and message id
+    /// was already seen within the dedup window, in which case the frame is a copy that
+    /// came around a loop or over a redundant link. The sender is reachable over `link`
+    /// either way.
+    fn learn(&mut self, header: &Header, msgid: u32, link: usize, now: Instant) -> bool {
+        let route = self.routes
+            .entry((header.system_id, header.component_id, link))
+            .or_insert(Route {
+                system_id: header.system_id,
+                component_id: header.component_id,
+                link: link,
+                last_seen: now,
+            });
+        route.last_seen = now;
+
+        while let Some(&(id, seen)) = self.recent.front() {
+            if now.duration_since(seen) < self.dedup_window {
+                break;
+            }
+            self.recent.pop_front();
+            if self.recent_ids.get(&id) == Some(&seen) {
+                self.recent_ids.remove(&id);
+            }
+        }
+
+        let id = (header.system_id, header.component_id, header.sequence, msgid);
+        if self.recent_ids.contains_key(&id) {
+            return false;
+        }
+        self.recent.push_back((id, now));
+        self.recent_ids.insert(id, now);
+        true
+    }
+
+    /// Whether a component still uses the link of `route`, that is whether it was heard
+    /// there within the route timeout of when it was last heard on any link
+    fn is_current(&self, route: &Route) -> bool {
+        self.routes.values().all(|other| {
+            other.system_id != route.system_id
+                || other.component_id != route.component_id
+                || other.last_seen <= route.last_seen
+                || other.last_seen.duration_since(route.last_seen) < self.timeout
+        })
+    }
+
+    /// Links of the current routes that match `filter`
+    fn links<F: Fn(&Route) -> bool>(&self, filter: F) -> Vec<usize> {
+        self.routes
+            .values()
+            .filter(|route| filter(route) && self.is_current(route))
+            .map(|route| route.link)
+            .collect()
+    }
+
+    /// Links a frame received on `from` is forwarded to.
+    ///
+    /// Frames without a target system, or with target system 0, go to every other link.
+    /// Otherwise they go to the links the target component is heard on or, if it was
+    /// not heard anywhere, to the links the target system is heard on. A component heard
+    /// on several links is reached over all of them, until one of them stays silent for
+    /// the route timeout.
+    fn destinations(&self, from: usize, links: usize, target: Option<(u8, u8)>) -> Vec<usize> {
+        let (target_system, target_component) = match target {
+            Some((0, _)) | None => return (0..links).filter(|&link| link != from).collect(),
+            Some(target) => target,
+        };
+        let mut destinations = self.links(|route| {
+            route.system_id == target_system && (target_component == 0 || route.component_id == target_component)
+        });
+        if destinations.is_empty() && target_component != 0 {
+            destinations = self.links(|route| route.system_id == target_system);
+        }
+        destinations.sort();
+        destinations.dedup();
+        destinations.retain(|&link| link != from);
+        destinations
+    }
+}
+
+/// Forwards frames between several connections, like `mavlink-router`.
+///
+/// The router learns which systems and components are behind which link from the frames
+/// they send. Messages with a `target_system` field are forwarded only to the links their
+/// target was heard on, and messages without one to every other link. Frames are never
+/// sent back on the link they came from, and a frame whose sender, sequence number and
+/// message id were seen shortly before is dropped as a copy that came around a loop or
+/// over a redundant link. Messages to systems that were never heard are dropped.
+///
+/// ```ignore
+/// let mut router = Router::new();
+/// router.add_link(connect("serial:/dev/ttyACM0:115200")?);
+/// router.add_link(connect("udpin:0.0.0.0:14550")?);
+/// let local = router.local_link();
+/// let handle = router.start();
+/// ```
+pub struct Router {
+    links: Vec<Arc<Connection>>,
+    routes: Arc<Mutex<RouteTable>>,
+}
+
+/// Handle on a started `Router`
+pub struct RouterHandle {
+    routes: Arc<Mutex<RouteTable>>,
+    threads: Vec<thread::JoinHandle<io::Error>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            links: Vec::new(),
+            routes: Arc::new(Mutex::new(RouteTable::new())),
+        }
+    }
+
+    /// Add a connection to route between, returning the index of its link
+    pub fn add_link(&mut self, conn: Connection) -> usize {
+        self.links.push(Arc::new(conn));
+        self.links.len() - 1
+    }
+
+    /// Add an in-memory link, returning the other end for the application to use like any
+    /// other connection
+    pub fn local_link(&mut self) -> MockConnection {
+        let (local, link) = channel_pair();
+        self.add_link(Box::new(link));
+        local
+    }
+
+    /// Set how long a component stays bound to a link it is no longer heard on, once it
+    /// is heard on another one
+    pub fn set_route_timeout(&mut self, timeout: Duration) {
+        self.routes.lock().unwrap().timeout = timeout;
+    }
+
+    /// Set how long a forwarded frame is remembered to drop copies of it
+    pub fn set_dedup_window(&mut self, window: Duration) {
+        self.routes.lock().unwrap().dedup_window = window;
+    }
+
+    /// Start a thread per link that forwards everything received on it
+    pub fn start(self) -> RouterHandle {
+        let Router { links, routes } = self;
+        let links = Arc::new(links);
+        let threads = (0..links.len())
+            .map(|from| {
+                let links = links.clone();
+                let routes = routes.clone();
+                thread::spawn(move || forward_link(&links, &routes, from))
+            })
+            .collect();
+        RouterHandle {
+            routes: routes,
+            threads: threads,
+        }
+    }
+}
+
+impl RouterHandle {
+    /// Every component heard so far, once for every link it was heard on
+    pub fn routes(&self) -> Vec<Route> {
+        let mut routes: Vec<Route> = self.routes.lock().unwrap().routes.values().cloned().collect();
+        routes.sort_by_key(|route| (route.system_id, route.component_id, route.link));
+        routes
+    }
+
+    /// Wait until every link has failed, returning the error each failed with
+    pub fn join(self) -> Vec<io::Error> {
+        self.threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| io::Error::new(io::ErrorKind::Other, "router thread panicked"))
+            })
+            .collect()
+    }
+}
+
+/// Forward the frames received on link `from` until it fails
+fn forward_link(links: &[Arc<Connection>], routes: &Mutex<RouteTable>, from: usize) -> io::Error {
+    loop {
+        match links[from].recv_frame() {
+            Ok(frame) => route(links, routes, from, &frame),
+            Err(e) => {
+                if is_link_failure(&e) {
+                    return e;
+                }
+            }
+        }
+    }
+}
+
+fn route(links: &[Arc<Connection>], routes: &Mutex<RouteTable>, from: usize, frame: &MavFrame) {
+    let destinations = {
+        let mut routes = routes.lock().unwrap();
+        if !routes.learn(&frame.header, frame.msg.message_id(), from, Instant::now()) {
+            return;
+        }
+        let target = frame
+            .msg
+            .target_system()
+            .map(|system| (system, frame.msg.target_component().unwrap_or(0)));
+        routes.destinations(from, links.len(), target)
+    };
+    for link in destinations {
+        // a failing link is noticed by its own thread; the others carry on
+        let _ = links[link].send_frame(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(system_id: u8, component_id: u8, sequence: u8) -> Header {
+        Header {
+            system_id: system_id,
+            component_id: component_id,
+            sequence: sequence,
+        }
+    }
+
+    #[test]
+    fn learn_drops_copies_within_window() {
+        let mut routes = RouteTable::new();
+        let now = Instant::now();
+        assert!(routes.learn(&header(1, 1, 7), 0, 0, now));
+        // the same frame back over another link, or again over the same one
+        assert!(!routes.learn(&header(1, 1, 7), 0, 1, now + Duration::from_millis(10)));
+        assert!(!routes.learn(&header(1, 1, 7), 0, 0, now + Duration::from_millis(20)));
+        // a different sequence number, message id or sender is a new frame
+        assert!(routes.learn(&header(1, 1, 8), 0, 1, now + Duration::from_millis(30)));
+        assert!(routes.learn(&header(1, 1, 7), 1, 1, now + Duration::from_millis(30)));
+        assert!(routes.learn(&header(1, 2, 7), 0, 1, now + Duration::from_millis(30)));
+        // sequence numbers wrap around, so an old one is a new frame after the window
+        assert!(routes.learn(&header(1, 1, 7), 0, 0, now + DEFAULT_DEDUP_WINDOW));
+    }
+
+    #[test]
+    fn learn_records_every_link() {
+        let mut routes = RouteTable::new();
+        let now = Instant::now();
+        routes.learn(&header(1, 1, 0), 0, 0, now);
+        routes.learn(&header(1, 1, 0), 0, 2, now);
+        let mut links: Vec<usize> = routes.routes.values().map(|route| route.link).collect();
+        links.sort();
+        assert_eq!(links, vec![0, 2]);
+    }
+
+    #[test]
+    fn broadcasts_go_to_every_other_link() {
+        let routes = RouteTable::new();
+        assert_eq!(routes.destinations(1, 3, None), vec![0, 2]);
+        assert_eq!(routes.destinations(1, 3, Some((0, 0))), vec![0, 2]);
+    }
+
+    #[test]
+    fn targeted_messages_follow_routes() {
+        let mut routes = RouteTable::new();
+        let now = Instant::now();
+        routes.learn(&header(1, 1, 0), 0, 0, now);
+        routes.learn(&header(1, 2, 0), 0, 1, now);
+        routes.learn(&header(2, 1, 0), 0, 2, now);
+
+        assert_eq!(routes.destinations(3, 4, Some((1, 1))), vec![0]);
+        assert_eq!(routes.destinations(3, 4, Some((1, 0))), vec![0, 1]);
+        // an unknown component of a known system goes to every link of the system
+        assert_eq!(routes.destinations(3, 4, Some((1, 5))), vec![0, 1]);
+        // never back where it came from, and nowhere for unknown systems
+        assert_eq!(routes.destinations(0, 4, Some((1, 1))), Vec::<usize>::new());
+        assert_eq!(routes.destinations(3, 4, Some((9, 1))), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn redundant_links_are_all_used() {
+        let mut routes = RouteTable::new();
+        let now = Instant::now();
+        routes.learn(&header(1, 1, 0), 0, 0, now);
+        routes.learn(&header(1, 1, 0), 0, 1, now);
+        assert_eq!(routes.destinations(2, 3, Some((1, 1))), vec![0, 1]);
+
+        // link 1 goes silent while link 0 carries on
+        let later = now + DEFAULT_ROUTE_TIMEOUT;
+        routes.learn(&header(1, 1, 1), 0, 0, later);
+        assert_eq!(routes.destinations(2, 3, Some((1, 1))), vec![0]);
+    }
+}
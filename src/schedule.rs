@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use MavFrame;
+
+struct Scheduled {
+    due: Instant,
+    /// Order of scheduling, so frames due at the same time keep their order
+    order: u64,
+    frame: MavFrame,
+}
+
+/// Frames waiting for their delivery time
+pub struct Schedule {
+    pending: Vec<Scheduled>,
+    next_order: u64,
+    /// Set when whoever schedules the frames goes away
+    pub closed: bool,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule {
+            pending: Vec::new(),
+            next_order: 0,
+            closed: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn push(&mut self, due: Instant, frame: MavFrame) {
+        let order = self.next_order;
+        self.next_order += 1;
+        self.pending.push(Scheduled {
+            due: due,
+            order: order,
+            frame: frame,
+        });
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|scheduled| scheduled.due).min()
+    }
+
+    /// Take the earliest frame if it is due
+    pub fn pop_due(&mut self, now: Instant) -> Option<MavFrame> {
+        let next = self.pending
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, scheduled)| (scheduled.due, scheduled.order))
+            .map(|(i, scheduled)| (i, scheduled.due));
+        match next {
+            Some((i, due)) if due <= now => Some(self.pending.remove(i).frame),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+
+/// Seconds between the unix epoch and the MAVLink signing epoch (1 January 2015 GMT)
+const SIGNING_EPOCH: u64 = 1420070400;
+
+/// Oldest timestamp accepted for a stream we have not heard from before,
+/// in the 10 microsecond units of the signing timestamp (one minute)
+const NEW_STREAM_MAX_AGE: u64 = 60 * 100_000;
+
+/// Length of the MAVLink 2 signature trailer (link id, timestamp, signature)
+pub const SIGNATURE_LEN: usize = 13;
+
+/// Configuration of MAVLink 2 message signing for a link
+#[derive(Debug, Clone)]
+pub struct SigningConfig {
+    secret_key: [u8; 32],
+    link_id: u8,
+    sign_outgoing: bool,
+    allow_unsigned: bool,
+}
+
+impl SigningConfig {
+    /// Create a signing configuration.
+    ///
+    /// `link_id` identifies this link in outgoing signatures. If `sign_outgoing` is set,
+    /// every MAVLink 2 frame sent is signed. If `allow_unsigned` is set, unsigned (and
+    /// MAVLink 1) frames are still accepted; signed frames are always verified.
+    pub fn new(secret_key: [u8; 32], link_id: u8, sign_outgoing: bool, allow_unsigned: bool) -> SigningConfig {
+        SigningConfig {
+            secret_key: secret_key,
+            link_id: link_id,
+            sign_outgoing: sign_outgoing,
+            allow_unsigned: allow_unsigned,
+        }
+    }
+
+    /// Derive a secret key from a passphrase, the same way MAVProxy and QGroundControl do
+    pub fn key_from_passphrase(passphrase: &str) -> [u8; 32] {
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha256::digest(passphrase.as_bytes()));
+        key
+    }
+
+    pub fn link_id(&self) -> u8 {
+        self.link_id
+    }
+
+    pub fn sign_outgoing(&self) -> bool {
+        self.sign_outgoing
+    }
+
+    pub fn allow_unsigned(&self) -> bool {
+        self.allow_unsigned
+    }
+}
+
+/// Signing state of a link.
+///
+/// Holds the configuration together with the local signing timestamp and the last
+/// timestamp seen on every (system id, component id, link id) stream, which is used
+/// to reject replayed frames.
+pub struct SigningData {
+    config: SigningConfig,
+    state: Mutex<SigningState>,
+}
+
+struct SigningState {
+    timestamp: u64,
+    stream_timestamps: HashMap<(u8, u8, u8), u64>,
+}
+
+impl SigningState {
+    /// Advance the local timestamp to the current time, never moving it backwards
+    fn update_timestamp(&mut self) {
+        let now = signing_timestamp_now();
+        if now > self.timestamp {
+            self.timestamp = now;
+        }
+    }
+}
+
+impl SigningData {
+    pub fn from_config(config: SigningConfig) -> SigningData {
+        SigningData {
+            config: config,
+            state: Mutex::new(SigningState {
+                timestamp: 0,
+                stream_timestamps: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &SigningConfig {
+        &self.config
+    }
+
+    /// Verify the signature of a received frame.
+    ///
+    /// `frame` holds the frame from the start byte up to and including the CRC. A frame
+    /// is rejected if its signature does not match, or if its timestamp is not newer than
+    /// the last one seen on its stream (or older than a minute for a new stream).
+    pub fn verify(&self, frame: &[u8], signature: &[u8; SIGNATURE_LEN], system_id: u8, component_id: u8) -> bool {
+        let link_id = signature[0];
+        let timestamp = LittleEndian::read_uint(&signature[1..7], 6);
+
+        let mut state = self.state.lock().unwrap();
+        state.update_timestamp();
+
+        let stream = (system_id, component_id, link_id);
+        match state.stream_timestamps.get(&stream) {
+            Some(&last) if timestamp <= last => return false,
+            None if timestamp + NEW_STREAM_MAX_AGE < state.timestamp => return false,
+            _ => (),
+        }
+
+        if self.calculate(frame, &signature[..7]) != signature[7..] {
+            return false;
+        }
+
+        state.stream_timestamps.insert(stream, timestamp);
+        if timestamp > state.timestamp {
+            state.timestamp = timestamp;
+        }
+        true
+    }
+
+    /// Create the signature trailer for an outgoing frame.
+    ///
+    /// `frame` holds the frame from the start byte up to and including the CRC, with the
+    /// signed incompatibility flag already set.
+    pub fn sign(&self, frame: &[u8]) -> [u8; SIGNATURE_LEN] {
+        let mut state = self.state.lock().unwrap();
+        state.update_timestamp();
+        // every frame needs a unique timestamp
+        state.timestamp += 1;
+
+        let mut signature = [0; SIGNATURE_LEN];
+        signature[0] = self.config.link_id;
+        LittleEndian::write_uint(&mut signature[1..7], state.timestamp, 6);
+        let hash = self.calculate(frame, &signature[..7]);
+        signature[7..].copy_from_slice(&hash);
+        signature
+    }
+
+    /// First 48 bits of SHA-256 over the secret key, the frame, the link id and the timestamp
+    fn calculate(&self, frame: &[u8], link_id_timestamp: &[u8]) -> [u8; 6] {
+        let mut hasher = Sha256::default();
+        hasher.input(&self.config.secret_key);
+        hasher.input(frame);
+        hasher.input(link_id_timestamp);
+        let mut hash = [0; 6];
+        hash.copy_from_slice(&hasher.result()[..6]);
+        hash
+    }
+}
+
+/// Current time in the 10 microsecond units used by signing timestamps
+fn signing_timestamp_now() -> u64 {
+    let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let secs = since_unix.as_secs().saturating_sub(SIGNING_EPOCH);
+    secs * 100_000 + since_unix.subsec_nanos() as u64 / 10_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MAVLink 2 frame up to the CRC, with the signed flag set
+    const FRAME: [u8; 13] = [0xfd, 1, 1, 0, 7, 1, 1, 0, 0, 0, 0x42, 0x12, 0x34];
+
+    fn signing() -> SigningData {
+        let mut key = [0; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        SigningData::from_config(SigningConfig::new(key, 5, true, false))
+    }
+
+    /// Signature trailer with the given timestamp, signed like `sign` does
+    fn signature_at(signing: &SigningData, frame: &[u8], timestamp: u64) -> [u8; SIGNATURE_LEN] {
+        let mut signature = [0; SIGNATURE_LEN];
+        signature[0] = signing.config().link_id();
+        LittleEndian::write_uint(&mut signature[1..7], timestamp, 6);
+        let hash = signing.calculate(frame, &signature[..7]);
+        signature[7..].copy_from_slice(&hash);
+        signature
+    }
+
+    #[test]
+    fn key_from_passphrase_is_sha256() {
+        let key = SigningConfig::key_from_passphrase("test");
+        assert_eq!(key[..8], [0x9f, 0x86, 0xd0, 0x81, 0x88, 0x4c, 0x7d, 0x65]);
+        assert_eq!(key[31], 0x08);
+    }
+
+    #[test]
+    fn signature_known_answer() {
+        let link_id_timestamp = [5, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01];
+        assert_eq!(
+            signing().calculate(&FRAME, &link_id_timestamp),
+            [0xf3, 0x80, 0x28, 0x4b, 0x73, 0x67]
+        );
+    }
+
+    #[test]
+    fn sign_writes_link_id_and_48_bit_timestamp() {
+        let signing = signing();
+        let before = signing_timestamp_now();
+        let first = signing.sign(&FRAME);
+        let second = signing.sign(&FRAME);
+
+        assert_eq!(first[0], 5);
+        let first_timestamp = LittleEndian::read_uint(&first[1..7], 6);
+        let second_timestamp = LittleEndian::read_uint(&second[1..7], 6);
+        assert!(first_timestamp >= before && first_timestamp < 1 << 48);
+        // every frame gets a new timestamp, even within the same 10 microseconds
+        assert!(second_timestamp > first_timestamp);
+    }
+
+    #[test]
+    fn verify_accepts_signed_frame() {
+        let sender = signing();
+        let receiver = signing();
+        let signature = sender.sign(&FRAME);
+        assert!(receiver.verify(&FRAME, &signature, 1, 1));
+    }
+
+    #[test]
+    fn verify_rejects_bad_signature() {
+        let sender = signing();
+        let receiver = signing();
+        let signature = sender.sign(&FRAME);
+
+        let mut tampered = FRAME;
+        tampered[10] ^= 1;
+        assert!(!receiver.verify(&tampered, &signature, 1, 1));
+
+        let other = SigningData::from_config(SigningConfig::new([0xff; 32], 5, true, false));
+        assert!(!receiver.verify(&FRAME, &other.sign(&FRAME), 1, 1));
+    }
+
+    #[test]
+    fn verify_rejects_replayed_and_older_timestamps() {
+        let receiver = signing();
+        let now = signing_timestamp_now();
+        let signature = signature_at(&receiver, &FRAME, now);
+        assert!(receiver.verify(&FRAME, &signature, 1, 1));
+        // the same frame again is a replay
+        assert!(!receiver.verify(&FRAME, &signature, 1, 1));
+        // as is an older one on the same stream
+        assert!(!receiver.verify(&FRAME, &signature_at(&receiver, &FRAME, now - 1), 1, 1));
+        assert!(receiver.verify(&FRAME, &signature_at(&receiver, &FRAME, now + 1), 1, 1));
+        // other streams keep their own timestamps
+        assert!(receiver.verify(&FRAME, &signature_at(&receiver, &FRAME, now - 1), 2, 1));
+    }
+
+    #[test]
+    fn verify_rejects_stale_timestamp_on_new_stream() {
+        let receiver = signing();
+        let stale = signing_timestamp_now() - NEW_STREAM_MAX_AGE - 100_000;
+        assert!(!receiver.verify(&FRAME, &signature_at(&receiver, &FRAME, stale), 1, 1));
+        let recent = signing_timestamp_now() - NEW_STREAM_MAX_AGE / 2;
+        assert!(receiver.verify(&FRAME, &signature_at(&receiver, &FRAME, recent), 1, 1));
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::MavMessage;
+use connection::{Connection, MavConnection};
+use error::{ReadErrorCounts, RecvTimeoutError};
+use stats::LinkStats;
+use {MavFrame, MavlinkVersion};
+
+/// Split a connection into a sending and a receiving half.
+///
+/// The halves can move to different threads, and the sender can be cloned for every
+/// producer thread. Connections keep their read and write state apart, so a thread
+/// blocked in `recv` does not hold up sends. Settings such as the source ids and signing
+/// must be made before splitting.
+pub fn split(conn: Connection) -> (MavSender, MavReceiver) {
+    let conn: Arc<MavConnection + Send + Sync> = Arc::from(conn);
+    (MavSender { conn: conn.clone() }, MavReceiver { conn: conn })
+}
+
+/// Sending half of a connection, see `split`
+#[derive(Clone)]
+pub struct MavSender {
+    conn: Arc<MavConnection + Send + Sync>,
+}
+
+impl MavSender {
+    /// Send a message with the source ids and protocol version of the connection
+    pub fn send(&self, data: &MavMessage) -> io::Result<()> {
+        self.conn.send(data)
+    }
+
+    /// Send a message with an explicit header and protocol version
+    pub fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        self.conn.send_frame(frame)
+    }
+
+    pub fn get_source_ids(&self) -> (u8, u8) {
+        self.conn.get_source_ids()
+    }
+
+    pub fn get_protocol_version(&self) -> MavlinkVersion {
+        self.conn.get_protocol_version()
+    }
+
+    /// Snapshot of the statistics of the whole link
+    pub fn link_stats(&self) -> LinkStats {
+        self.conn.link_stats()
+    }
+}
+
+/// Receiving half of a connection, see `split`
+pub struct MavReceiver {
+    conn: Arc<MavConnection + Send + Sync>,
+}
+
+impl MavReceiver {
+    /// Receive a mavlink message, blocking until one arrives
+    pub fn recv(&self) -> io::Result<MavMessage> {
+        self.conn.recv()
+    }
+
+    /// Receive a mavlink message along with its protocol version and receive time
+    pub fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.conn.recv_frame()
+    }
+
+    /// Receive a frame, giving up after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.conn.recv_timeout(timeout)
+    }
+
+    /// Receive a frame if one is ready
+    pub fn try_recv(&self) -> Result<MavFrame, RecvTimeoutError> {
+        self.conn.try_recv()
+    }
+
+    /// Number of received frames skipped so far, by reason
+    pub fn read_error_counts(&self) -> ReadErrorCounts {
+        self.conn.read_error_counts()
+    }
+
+    /// Snapshot of the statistics of the whole link
+    pub fn link_stats(&self) -> LinkStats {
+        self.conn.link_stats()
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use error::ReadErrorCounts;
+use throttle::DropPolicy;
+use Header;
+
+/// Sequence numbers remembered behind the latest one of a stream, to tell late and
+/// duplicated packets apart
+const SEQUENCE_WINDOW: u8 = 64;
+
+/// Shortest interval over which message and byte rates are measured
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Snapshot of the statistics of a link
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkStats {
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Packets missing from the received sequence numbers, over all streams
+    pub packets_dropped: u64,
+    /// Packets received again with a recent sequence number, over all streams
+    pub packets_duplicated: u64,
+    /// Packets received after later ones, over all streams
+    pub packets_reordered: u64,
+    /// Received frames skipped because they could not be decoded
+    pub read_errors: ReadErrorCounts,
+    pub rx_messages_per_sec: f64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_messages_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    /// Per (system id, component id) sequence statistics
+    pub streams: Vec<StreamStats>,
+    /// Outgoing queue of a rate limited link, see `ThrottledConnection`
+    pub outbound_queue: Option<QueueStats>,
+}
+
+impl LinkStats {
+    /// Number of received frames that failed the CRC check
+    pub fn crc_failures(&self) -> u64 {
+        self.read_errors.bad_crc
+    }
+
+    /// Fraction of packets lost over all streams, between 0 and 1
+    pub fn loss_rate(&self) -> f64 {
+        loss_rate(self.messages_received, self.packets_dropped)
+    }
+}
+
+/// Statistics of the outgoing queue of a rate limited link
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages currently queued
+    pub depth: usize,
+    pub max_depth: usize,
+    /// Messages that may be queued before the drop policy applies
+    pub limit: usize,
+    pub policy: DropPolicy,
+    /// Messages queued because the byte budget was used up
+    pub delayed: u64,
+    /// Messages dropped by the drop policy
+    pub dropped: u64,
+    /// Queued messages that failed to send
+    pub send_failures: u64,
+}
+
+/// Sequence statistics of the messages received from one system and component
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StreamStats {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub received: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    /// Packets received after later ones; they are not counted as dropped
+    pub reordered: u64,
+    pub last_sequence: u8,
+}
+
+impl StreamStats {
+    /// Fraction of packets lost on this stream, between 0 and 1
+    pub fn loss_rate(&self) -> f64 {
+        loss_rate(self.received, self.dropped)
+    }
+}
+
+fn loss_rate(received: u64, dropped: u64) -> f64 {
+    if received + dropped == 0 {
+        0.0
+    } else {
+        dropped as f64 / (received + dropped) as f64
+    }
+}
+
+/// Rate of a monotonically increasing counter, measured over windows of at least `RATE_WINDOW`
+struct RateMeter {
+    window_start: Instant,
+    window_count: u64,
+    rate: f64,
+}
+
+impl RateMeter {
+    fn new(now: Instant) -> RateMeter {
+        RateMeter {
+            window_start: now,
+            window_count: 0,
+            rate: 0.0,
+        }
+    }
+
+    fn update(&mut self, total: u64, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.rate = (total - self.window_count) as f64 / as_secs_f64(elapsed);
+            self.window_start = now;
+            self.window_count = total;
+        }
+    }
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+/// Sequence tracking of one stream
+struct Stream {
+    stats: StreamStats,
+    /// Bit `i` is set if sequence number `last_sequence - i` was received
+    recent: u64,
+}
+
+impl Stream {
+    fn receive(&mut self, sequence: u8) {
+        let stats = &mut self.stats;
+        stats.received += 1;
+        let ahead = sequence.wrapping_sub(stats.last_sequence);
+        if ahead == 0 {
+            stats.duplicated += 1;
+        } else if ahead <= 128 {
+            stats.dropped += ahead as u64 - 1;
+            self.recent = if ahead < SEQUENCE_WINDOW { (self.recent << ahead) | 1 } else { 1 };
+            stats.last_sequence = sequence;
+        } else {
+            let behind = stats.last_sequence.wrapping_sub(sequence);
+            if behind < SEQUENCE_WINDOW {
+                if self.recent & 1 << behind != 0 {
+                    stats.duplicated += 1;
+                } else {
+                    // counted as dropped when the gap was seen
+                    stats.reordered += 1;
+                    stats.dropped = stats.dropped.saturating_sub(1);
+                    self.recent |= 1 << behind;
+                }
+            } else {
+                // too far back to be late, the sender likely restarted
+                stats.last_sequence = sequence;
+                self.recent = 1;
+            }
+        }
+    }
+}
+
+struct Counters {
+    messages_received: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    streams: BTreeMap<(u8, u8), Stream>,
+    rx_messages_rate: RateMeter,
+    rx_bytes_rate: RateMeter,
+    tx_messages_rate: RateMeter,
+    tx_bytes_rate: RateMeter,
+}
+
+impl Counters {
+    fn update_rates(&mut self, now: Instant) {
+        self.rx_messages_rate.update(self.messages_received, now);
+        self.rx_bytes_rate.update(self.bytes_received, now);
+        self.tx_messages_rate.update(self.messages_sent, now);
+        self.tx_bytes_rate.update(self.bytes_sent, now);
+    }
+}
+
+/// Collects the statistics of a link as frames are sent and received
+pub struct StatsTracker {
+    counters: Mutex<Counters>,
+}
+
+impl StatsTracker {
+    pub fn new() -> StatsTracker {
+        let now = Instant::now();
+        StatsTracker {
+            counters: Mutex::new(Counters {
+                messages_received: 0,
+                messages_sent: 0,
+                bytes_received: 0,
+                bytes_sent: 0,
+                streams: BTreeMap::new(),
+                rx_messages_rate: RateMeter::new(now),
+                rx_bytes_rate: RateMeter::new(now),
+                tx_messages_rate: RateMeter::new(now),
+                tx_bytes_rate: RateMeter::new(now),
+            }),
+        }
+    }
+
+    /// Count bytes read from the link, whether or not they formed a valid frame
+    pub fn record_bytes_received(&self, len: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.bytes_received += len as u64;
+        counters.update_rates(Instant::now());
+    }
+
+    /// Count a received message and check its sequence number for gaps.
+    ///
+    /// A sequence number up to half the range ahead of the last one counts the packets
+    /// in between as dropped. One behind it is a late or duplicated packet if it is within
+    /// the last `SEQUENCE_WINDOW` numbers, and otherwise a restart of the sender.
+    pub fn record_received(&self, header: &Header) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.messages_received += 1;
+
+        let key = (header.system_id, header.component_id);
+        counters
+            .streams
+            .entry(key)
+            .or_insert(Stream {
+                stats: StreamStats {
+                    system_id: header.system_id,
+                    component_id: header.component_id,
+                    received: 0,
+                    dropped: 0,
+                    duplicated: 0,
+                    reordered: 0,
+                    last_sequence: header.sequence.wrapping_sub(1),
+                },
+                recent: 0,
+            })
+            .receive(header.sequence);
+
+        counters.update_rates(Instant::now());
+    }
+
+    /// Count a message sent as a frame of `len` bytes
+    pub fn record_sent(&self, len: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.messages_sent += 1;
+        counters.bytes_sent += len as u64;
+        counters.update_rates(Instant::now());
+    }
+
+    /// Take a snapshot of the statistics
+    pub fn snapshot(&self, read_errors: ReadErrorCounts) -> LinkStats {
+        let mut counters = self.counters.lock().unwrap();
+        counters.update_rates(Instant::now());
+        let streams: Vec<StreamStats> = counters.streams.values().map(|stream| stream.stats).collect();
+        LinkStats {
+            messages_received: counters.messages_received,
+            messages_sent: counters.messages_sent,
+            bytes_received: counters.bytes_received,
+            bytes_sent: counters.bytes_sent,
+            packets_dropped: streams.iter().map(|s| s.dropped).sum(),
+            packets_duplicated: streams.iter().map(|s| s.duplicated).sum(),
+            packets_reordered: streams.iter().map(|s| s.reordered).sum(),
+            read_errors: read_errors,
+            rx_messages_per_sec: counters.rx_messages_rate.rate,
+            rx_bytes_per_sec: counters.rx_bytes_rate.rate,
+            tx_messages_per_sec: counters.tx_messages_rate.rate,
+            tx_bytes_per_sec: counters.tx_bytes_rate.rate,
+            streams: streams,
+            outbound_queue: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Statistics of a single stream after receiving the given sequence numbers
+    fn receive(sequences: &[u8]) -> StreamStats {
+        let tracker = StatsTracker::new();
+        for &sequence in sequences {
+            tracker.record_received(&Header {
+                sequence: sequence,
+                system_id: 1,
+                component_id: 1,
+            });
+        }
+        tracker.snapshot(ReadErrorCounts::default()).streams[0]
+    }
+
+    #[test]
+    fn counts_gaps_as_dropped() {
+        let stats = receive(&[10, 11, 14, 15]);
+        assert_eq!((stats.received, stats.dropped, stats.duplicated), (4, 2, 0));
+        assert_eq!(stats.last_sequence, 15);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let stats = receive(&[254, 255, 0, 2]);
+        assert_eq!((stats.dropped, stats.reordered), (1, 0));
+    }
+
+    #[test]
+    fn late_packet_is_reordered_not_dropped() {
+        let stats = receive(&[1, 2, 4, 3, 5]);
+        assert_eq!((stats.dropped, stats.reordered, stats.duplicated), (0, 1, 0));
+        assert_eq!(stats.last_sequence, 5);
+    }
+
+    #[test]
+    fn detects_duplicates_out_of_order() {
+        let stats = receive(&[1, 2, 2, 3, 1, 4]);
+        assert_eq!((stats.dropped, stats.reordered, stats.duplicated), (0, 0, 2));
+    }
+
+    #[test]
+    fn sender_restart_is_not_loss() {
+        let stats = receive(&[100, 101, 102, 0, 1, 2]);
+        assert_eq!((stats.dropped, stats.reordered, stats.duplicated), (0, 0, 0));
+        assert_eq!(stats.last_sequence, 2);
+    }
+}
@@ -0,0 +1,542 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::MavMessage;
+use connection::{Connection, ErrorHandler, MavConnection};
+use error::{ReadErrorCounts, RecvTimeoutError};
+use signing::SIGNATURE_LEN;
+use stats::{LinkStats, QueueStats};
+use {unsigned_frame_len, MavFrame, MavlinkVersion, SigningConfig};
+
+/// Frames queued before the drop policy applies, by default
+const DEFAULT_QUEUE_LIMIT: usize = 100;
+
+/// Longest MAVLink frame, signed MAVLink 2 with a full payload
+const MAX_FRAME_LEN: usize = 280;
+
+/// Priority of outgoing messages on a `ThrottledConnection`, highest sent first
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Critical,
+}
+
+const PRIORITIES: [Priority; 4] = [Priority::Low, Priority::Normal, Priority::High, Priority::Critical];
+
+/// What a `ThrottledConnection` drops when a message is sent with its queue full.
+///
+/// When the message being sent is dropped, `send` fails with `WouldBlock`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the message being sent
+    Newest,
+    /// Drop the message queued the longest
+    Oldest,
+    /// Drop the oldest message of the lowest priority queued, or the message being sent if
+    /// its priority is lower still
+    LowestPriority,
+}
+
+/// Message waiting to be sent
+enum Outgoing {
+    /// Sent with the sequence number and source ids of the wrapped connection
+    Message(MavMessage),
+    Frame(MavFrame),
+}
+
+struct Queued {
+    outgoing: Outgoing,
+    len: usize,
+    /// Order of queueing, to find the oldest message over all priorities
+    order: u64,
+}
+
+struct Queue {
+    /// Queued messages by priority, in the order of `PRIORITIES`
+    levels: Vec<VecDeque<Queued>>,
+    next_order: u64,
+    bytes_per_sec: f64,
+    burst: f64,
+    /// Bytes that may be sent right away, up to `burst`
+    tokens: f64,
+    refilled: Instant,
+    limit: usize,
+    policy: DropPolicy,
+    stats: QueueStats,
+    /// Set when the connection is dropped, to stop the sending thread
+    closed: bool,
+}
+
+impl Queue {
+    fn new(bytes_per_sec: u32) -> Queue {
+        let burst = MAX_FRAME_LEN as f64;
+        Queue {
+            levels: PRIORITIES.iter().map(|_| VecDeque::new()).collect(),
+            next_order: 0,
+            bytes_per_sec: bytes_per_sec.max(1) as f64,
+            burst: burst,
+            tokens: burst,
+            refilled: Instant::now(),
+            limit: DEFAULT_QUEUE_LIMIT,
+            policy: DropPolicy::LowestPriority,
+            stats: QueueStats {
+                depth: 0,
+                max_depth: 0,
+                limit: DEFAULT_QUEUE_LIMIT,
+                policy: DropPolicy::LowestPriority,
+                delayed: 0,
+                dropped: 0,
+                send_failures: 0,
+            },
+            closed: false,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.burst);
+        self.refilled = now;
+    }
+
+    /// Tokens needed to send `len` bytes, so frames larger than the burst are not stuck
+    fn cost(&self, len: usize) -> f64 {
+        (len as f64).min(self.burst)
+    }
+
+    /// Take the next message to send if the budget allows it, or else return how long
+    /// to wait for it
+    fn pop(&mut self, now: Instant) -> Result<Queued, Option<Duration>> {
+        self.refill(now);
+        let level = match self.levels.iter().rposition(|level| !level.is_empty()) {
+            Some(level) => level,
+            None => return Err(None),
+        };
+        let cost = self.cost(self.levels[level][0].len);
+        if self.tokens < cost {
+            let wait = (cost - self.tokens) / self.bytes_per_sec;
+            return Err(Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32)));
+        }
+        self.tokens -= cost;
+        Ok(self.levels[level].pop_front().unwrap())
+    }
+
+    /// Queue a message, applying the drop policy if the queue is full.
+    ///
+    /// Fails with `WouldBlock` if the policy drops the message itself.
+    fn push(&mut self, priority: Priority, outgoing: Outgoing, len: usize) -> io::Result<()> {
+        if self.depth() >= self.limit {
+            let victim = match self.policy {
+                DropPolicy::Newest => None,
+                DropPolicy::Oldest => self.levels
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(level, queued)| queued.front().map(|front| (front.order, level)))
+                    .min()
+                    .map(|(_, level)| level),
+                DropPolicy::LowestPriority => self.levels
+                    .iter()
+                    .position(|level| !level.is_empty())
+                    .filter(|&level| level <= priority as usize),
+            };
+            self.stats.dropped += 1;
+            match victim {
+                Some(level) => {
+                    self.levels[level].pop_front();
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "outgoing queue is full, message dropped",
+                    ))
+                }
+            }
+        }
+        let order = self.next_order;
+        self.next_order += 1;
+        self.levels[priority as usize].push_back(Queued {
+            outgoing: outgoing,
+            len: len,
+            order: order,
+        });
+        self.stats.delayed += 1;
+        let depth = self.depth();
+        if depth > self.stats.max_depth {
+            self.stats.max_depth = depth;
+        }
+        Ok(())
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+fn transmit(inner: &RwLock<Connection>, outgoing: &Outgoing) -> io::Result<()> {
+    let inner = inner.read().unwrap();
+    match *outgoing {
+        Outgoing::Message(ref msg) => inner.send(msg),
+        Outgoing::Frame(ref frame) => inner.send_frame(frame),
+    }
+}
+
+/// Send queued messages as the byte budget allows, until the connection is dropped
+fn send_queued(inner: Arc<RwLock<Connection>>, shared: Arc<Shared>) {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if queue.closed {
+            return;
+        }
+        queue = match queue.pop(Instant::now()) {
+            Ok(queued) => {
+                drop(queue);
+                let result = transmit(&inner, &queued.outgoing);
+                let mut queue = shared.queue.lock().unwrap();
+                if result.is_err() {
+                    queue.stats.send_failures += 1;
+                }
+                queue
+            }
+            Err(Some(wait)) => shared.changed.wait_timeout(queue, wait).unwrap().0,
+            Err(None) => shared.changed.wait(queue).unwrap(),
+        };
+    }
+}
+
+/// Connection wrapper limiting the rate of outgoing bytes, for slow links like radios.
+///
+/// Messages are sent right away while the byte budget allows it, and otherwise queued and
+/// sent from a background thread as the budget refills, highest priority first and in
+/// order within a priority. Commands and mode changes are high priority by default, so
+/// they overtake bulk traffic such as parameter uploads; other messages are normal
+/// priority unless set otherwise.
+///
+/// When the queue is full the drop policy decides what is lost, and `send` fails with
+/// `WouldBlock` if that is the message being sent. Queue depth, drops and failures
+/// sending queued messages are reported in the `outbound_queue` of the link statistics,
+/// as queued messages are sent after `send` has returned.
+///
+/// ```ignore
+/// // 57600 baud is about 5760 bytes per second, leave some for the other side
+/// let mut radio = ThrottledConnection::new(connect("serial:/dev/ttyUSB0:57600")?, 5000);
+/// radio.set_priority(23, Priority::Low); // PARAM_SET
+/// ```
+pub struct ThrottledConnection {
+    inner: Arc<RwLock<Connection>>,
+    shared: Arc<Shared>,
+    priorities: HashMap<u32, Priority>,
+    default_priority: Priority,
+    protocol_version: MavlinkVersion,
+    signed: bool,
+}
+
+impl ThrottledConnection {
+    /// Wrap a connection, limiting its outgoing traffic to `bytes_per_sec`.
+    ///
+    /// Bursts of up to one frame of the longest length are allowed.
+    pub fn new(conn: Connection, bytes_per_sec: u32) -> ThrottledConnection {
+        let protocol_version = conn.get_protocol_version();
+        let inner = Arc::new(RwLock::new(conn));
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::new(bytes_per_sec)),
+            changed: Condvar::new(),
+        });
+        thread::spawn({
+            let inner = inner.clone();
+            let shared = shared.clone();
+            move || send_queued(inner, shared)
+        });
+
+        let mut priorities = HashMap::new();
+        // SET_MODE, COMMAND_INT and COMMAND_LONG
+        for &msgid in &[11, 75, 76] {
+            priorities.insert(msgid, Priority::High);
+        }
+        ThrottledConnection {
+            inner: inner,
+            shared: shared,
+            priorities: priorities,
+            default_priority: Priority::Normal,
+            protocol_version: protocol_version,
+            signed: false,
+        }
+    }
+
+    /// Set the rate outgoing bytes are limited to
+    pub fn set_rate(&mut self, bytes_per_sec: u32) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.refill(Instant::now());
+        queue.bytes_per_sec = bytes_per_sec.max(1) as f64;
+        self.shared.changed.notify_all();
+    }
+
+    /// Set how many bytes may be sent at once after the link was idle
+    pub fn set_burst(&mut self, bytes: usize) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.refill(Instant::now());
+        queue.burst = bytes.max(1) as f64;
+        queue.tokens = queue.tokens.min(queue.burst);
+        self.shared.changed.notify_all();
+    }
+
+    /// Set the priority of the messages with id `msgid`
+    pub fn set_priority(&mut self, msgid: u32, priority: Priority) {
+        self.priorities.insert(msgid, priority);
+    }
+
+    /// Set the priority of messages without one of their own
+    pub fn set_default_priority(&mut self, priority: Priority) {
+        self.default_priority = priority;
+    }
+
+    /// Set how many messages may be queued before the drop policy applies
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.limit = limit;
+        queue.stats.limit = limit;
+    }
+
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.policy = policy;
+        queue.stats.policy = policy;
+    }
+
+    fn priority(&self, msg: &MavMessage) -> Priority {
+        *self.priorities.get(&msg.message_id()).unwrap_or(&self.default_priority)
+    }
+
+    /// Length of a message on the wire
+    fn frame_len(&self, version: MavlinkVersion, msg: &MavMessage) -> usize {
+        let len = unsigned_frame_len(version, msg);
+        if self.signed && version == MavlinkVersion::V2 {
+            len + SIGNATURE_LEN
+        } else {
+            len
+        }
+    }
+
+    fn schedule(&self, priority: Priority, outgoing: Outgoing, len: usize) -> io::Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.depth() == 0 {
+            queue.refill(Instant::now());
+            let cost = queue.cost(len);
+            if queue.tokens >= cost {
+                // within budget, send right away so errors are returned
+                queue.tokens -= cost;
+                drop(queue);
+                return transmit(&self.inner, &outgoing);
+            }
+        }
+        try!(queue.push(priority, outgoing, len));
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for ThrottledConnection {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl MavConnection for ThrottledConnection {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.inner.read().unwrap().recv_frame()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.inner.read().unwrap().recv_timeout(timeout)
+    }
+
+    fn send(&self, data: &MavMessage) -> io::Result<()> {
+        let len = self.frame_len(self.protocol_version, data);
+        self.schedule(self.priority(data), Outgoing::Message(data.clone()), len)
+    }
+
+    fn send_frame(&self, frame: &MavFrame) -> io::Result<()> {
+        let len = self.frame_len(frame.version, &frame.msg);
+        self.schedule(self.priority(&frame.msg), Outgoing::Frame(frame.clone()), len)
+    }
+
+    fn set_source_ids(&mut self, system_id: u8, component_id: u8) {
+        self.inner.write().unwrap().set_source_ids(system_id, component_id);
+    }
+
+    fn get_source_ids(&self) -> (u8, u8) {
+        self.inner.read().unwrap().get_source_ids()
+    }
+
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.protocol_version = version;
+        self.inner.write().unwrap().set_protocol_version(version);
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.protocol_version
+    }
+
+    fn setup_signing(&mut self, signing: Option<SigningConfig>) {
+        self.signed = signing.is_some();
+        self.inner.write().unwrap().setup_signing(signing);
+    }
+
+    fn set_error_handler(&mut self, handler: Option<ErrorHandler>) {
+        self.inner.write().unwrap().set_error_handler(handler);
+    }
+
+    fn read_error_counts(&self) -> ReadErrorCounts {
+        self.inner.read().unwrap().read_error_counts()
+    }
+
+    fn link_stats(&self) -> LinkStats {
+        let mut stats = self.inner.read().unwrap().link_stats();
+        let queue = self.shared.queue.lock().unwrap();
+        stats.outbound_queue = Some(QueueStats {
+            depth: queue.depth(),
+            ..queue.stats
+        });
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::HEARTBEAT_DATA;
+    use mock::MockConnection;
+
+    fn heartbeat() -> Outgoing {
+        Outgoing::Message(MavMessage::HEARTBEAT(HEARTBEAT_DATA::default()))
+    }
+
+    fn queue(limit: usize, policy: DropPolicy) -> Queue {
+        let mut queue = Queue::new(100);
+        queue.limit = limit;
+        queue.policy = policy;
+        queue
+    }
+
+    /// Order numbers of the queued messages, from the first to be sent to the last
+    fn drain(queue: &mut Queue) -> Vec<u64> {
+        let mut orders = Vec::new();
+        while let Some(level) = queue.levels.iter().rposition(|level| !level.is_empty()) {
+            orders.push(queue.levels[level].pop_front().unwrap().order);
+        }
+        orders
+    }
+
+    fn would_block(result: io::Result<()>) -> bool {
+        result.err().map(|e| e.kind()) == Some(io::ErrorKind::WouldBlock)
+    }
+
+    #[test]
+    fn token_bucket_limits_rate() {
+        let mut queue = queue(10, DropPolicy::Newest);
+        queue.burst = 100.0;
+        queue.tokens = 100.0;
+        let start = queue.refilled;
+        for _ in 0..3 {
+            queue.push(Priority::Normal, heartbeat(), 50).unwrap();
+        }
+
+        assert!(queue.pop(start).is_ok());
+        assert!(queue.pop(start).is_ok());
+        assert_eq!(queue.pop(start).err(), Some(Some(Duration::from_millis(500))));
+        assert_eq!(queue.pop(start + Duration::from_millis(250)).err(), Some(Some(Duration::from_millis(250))));
+        assert!(queue.pop(start + Duration::from_millis(500)).is_ok());
+        assert_eq!(queue.pop(start + Duration::from_millis(500)).err(), Some(None));
+
+        // an idle link saves up no more than the burst
+        queue.refill(start + Duration::from_secs(60));
+        assert_eq!(queue.tokens, 100.0);
+    }
+
+    #[test]
+    fn frames_larger_than_burst_are_not_stuck() {
+        let mut queue = queue(10, DropPolicy::Newest);
+        queue.burst = 10.0;
+        queue.tokens = 10.0;
+        let start = queue.refilled;
+        queue.push(Priority::Normal, heartbeat(), 50).unwrap();
+        assert!(queue.pop(start).is_ok());
+    }
+
+    #[test]
+    fn higher_priorities_are_sent_first() {
+        let mut queue = queue(10, DropPolicy::Newest);
+        for &priority in [Priority::Low, Priority::Normal, Priority::High, Priority::Critical, Priority::Normal].iter() {
+            queue.push(priority, heartbeat(), 10).unwrap();
+        }
+        assert_eq!(drain(&mut queue), vec![3, 2, 1, 4, 0]);
+    }
+
+    #[test]
+    fn newest_policy_fails_the_send() {
+        let mut queue = queue(2, DropPolicy::Newest);
+        queue.push(Priority::Normal, heartbeat(), 10).unwrap();
+        queue.push(Priority::Normal, heartbeat(), 10).unwrap();
+        assert!(would_block(queue.push(Priority::Critical, heartbeat(), 10)));
+        assert_eq!(drain(&mut queue), vec![0, 1]);
+        assert_eq!((queue.stats.delayed, queue.stats.dropped), (2, 1));
+    }
+
+    #[test]
+    fn oldest_policy_drops_longest_queued() {
+        let mut queue = queue(2, DropPolicy::Oldest);
+        queue.push(Priority::Normal, heartbeat(), 10).unwrap();
+        queue.push(Priority::High, heartbeat(), 10).unwrap();
+        queue.push(Priority::Low, heartbeat(), 10).unwrap();
+        assert_eq!(drain(&mut queue), vec![1, 2]);
+        assert_eq!((queue.stats.delayed, queue.stats.dropped), (3, 1));
+    }
+
+    #[test]
+    fn lowest_priority_policy_drops_least_important() {
+        let mut queue = queue(2, DropPolicy::LowestPriority);
+        queue.push(Priority::Normal, heartbeat(), 10).unwrap();
+        queue.push(Priority::Low, heartbeat(), 10).unwrap();
+        queue.push(Priority::High, heartbeat(), 10).unwrap();
+        // nothing queued is less important than a low priority message, so it is dropped itself
+        assert!(would_block(queue.push(Priority::Low, heartbeat(), 10)));
+        assert_eq!(drain(&mut queue), vec![2, 0]);
+        assert_eq!((queue.stats.delayed, queue.stats.dropped, queue.stats.max_depth), (3, 2, 2));
+    }
+
+    #[test]
+    fn queue_stats_in_link_stats() {
+        let mut conn = ThrottledConnection::new(Box::new(MockConnection::new()), 1);
+        conn.set_burst(1);
+        conn.set_queue_limit(2);
+        conn.set_drop_policy(DropPolicy::Newest);
+        let msg = MavMessage::HEARTBEAT(HEARTBEAT_DATA::default());
+        // the first goes out within the burst, the next wait a second each
+        for _ in 0..3 {
+            conn.send(&msg).unwrap();
+        }
+        assert_eq!(conn.send(&msg).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            conn.link_stats().outbound_queue,
+            Some(QueueStats {
+                depth: 2,
+                max_depth: 2,
+                limit: 2,
+                policy: DropPolicy::Newest,
+                delayed: 2,
+                dropped: 1,
+                send_failures: 0,
+            })
+        );
+    }
+}
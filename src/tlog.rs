@@ -0,0 +1,290 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use common::MavMessage;
+use connection::{LinkConfig, MavConnection};
+use error::{MessageReadError, RecvTimeoutError};
+use {read_raw_frame, write_raw, write_signed, MavFrame, RawFrame, ReceiveTime};
+
+/// Microseconds since the unix epoch, as stored before every frame of a tlog
+fn to_tlog_timestamp(time: SystemTime) -> u64 {
+    let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since_unix.as_secs() * 1_000_000 + since_unix.subsec_nanos() as u64 / 1_000
+}
+
+fn from_tlog_timestamp(timestamp: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(timestamp / 1_000_000, (timestamp % 1_000_000) as u32 * 1_000)
+}
+
+/// Read the next timestamp and frame of a tlog
+fn read_record<R: Read>(r: &mut R) -> Result<(u64, RawFrame), MessageReadError> {
+    let timestamp = try!(r.read_u64::<BigEndian>());
+    let frame = try!(read_raw_frame(r));
+    Ok((timestamp, frame))
+}
+
+struct TlogRead {
+    reader: BufReader<File>,
+    /// Next record, read but not yet due
+    pending: Option<(u64, RawFrame)>,
+    /// Timestamp of the first record and the time it was replayed
+    start: Option<(u64, Instant)>,
+}
+
+/// Replay of a telemetry log (`.tlog`) file as a MAVLink connection.
+///
+/// A tlog holds every frame of a link preceded by its 64-bit big-endian receive time in
+/// microseconds since the unix epoch, as written by `TlogWriter`, MAVProxy and
+/// QGroundControl. Frames are replayed either as fast as they can be read or, with a
+/// speed factor, paced to their recorded timing.
+///
+/// Replayed frames carry their recorded time as the wall clock receive time. Sent
+/// messages are discarded, and `recv` fails with `UnexpectedEof` at the end of the file.
+pub struct TlogFile {
+    read: Mutex<TlogRead>,
+    speed: Option<f64>,
+    link: LinkConfig,
+}
+
+impl TlogFile {
+    /// Open a tlog for replay.
+    ///
+    /// With a `speed`, frames are paced to their recorded timing, sped up by that factor;
+    /// without one they are replayed as fast as possible.
+    pub fn open<P: AsRef<Path>>(path: P, speed: Option<f64>) -> io::Result<TlogFile> {
+        if let Some(speed) = speed {
+            if !(speed > 0.0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Replay speed must be positive"));
+            }
+        }
+        let file = try!(File::open(path));
+        Ok(TlogFile {
+            read: Mutex::new(TlogRead {
+                reader: BufReader::new(file),
+                pending: None,
+                start: None,
+            }),
+            speed: speed,
+            link: LinkConfig::new(),
+        })
+    }
+
+    /// Replay the next frame, giving up at `deadline` if there is one
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<MavFrame, RecvTimeoutError> {
+        let mut guard = self.read.lock().unwrap();
+        let state = &mut *guard;
+        loop {
+            if state.pending.is_none() {
+                match read_record(&mut state.reader) {
+                    Ok(record) => state.pending = Some(record),
+                    Err(MessageReadError::Eof) => {
+                        return Err(RecvTimeoutError::Io(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "end of tlog",
+                        )))
+                    }
+                    Err(MessageReadError::Io(e)) => return Err(RecvTimeoutError::Io(e)),
+                    Err(e) => {
+                        self.link.errors.report(&e);
+                        continue;
+                    }
+                }
+            }
+
+            let timestamp = state.pending.as_ref().unwrap().0;
+            if let Some(speed) = self.speed {
+                let (start_timestamp, start_time) = *state.start.get_or_insert((timestamp, Instant::now()));
+                let offset_us = timestamp.saturating_sub(start_timestamp) as f64 / speed;
+                let due = start_time + Duration::new(
+                    (offset_us / 1e6) as u64,
+                    ((offset_us % 1e6) * 1e3) as u32,
+                );
+                let now = Instant::now();
+                if due > now {
+                    match deadline {
+                        Some(deadline) if deadline < due => {
+                            if deadline > now {
+                                thread::sleep(deadline - now);
+                            }
+                            return Err(RecvTimeoutError::Timeout);
+                        }
+                        _ => thread::sleep(due - now),
+                    }
+                }
+            }
+
+            let (timestamp, raw) = state.pending.take().unwrap();
+            self.link.stats.record_bytes_received(raw.wire_len());
+            match raw.to_frame(self.link.signing.as_ref()) {
+                Ok(mut frame) => {
+                    frame.received = Some(ReceiveTime {
+                        monotonic: Instant::now(),
+                        wall_clock: from_tlog_timestamp(timestamp),
+                    });
+                    self.link.stats.record_received(&frame.header);
+                    return Ok(frame);
+                }
+                Err(e) => self.link.errors.report(&e),
+            }
+        }
+    }
+}
+
+impl MavConnection for TlogFile {
+    fn recv_frame(&self) -> io::Result<MavFrame> {
+        self.recv_until(None).map_err(io::Error::from)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<MavFrame, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn send(&self, _data: &MavMessage) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn send_frame(&self, _frame: &MavFrame) -> io::Result<()> {
+        Ok(())
+    }
+
+    link_config_methods!();
+}
+
+/// Writer of telemetry log (`.tlog`) files
+pub struct TlogWriter<W: Write> {
+    writer: W,
+}
+
+impl TlogWriter<BufWriter<File>> {
+    /// Create a tlog file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<TlogWriter<BufWriter<File>>> {
+        let file = try!(File::create(path));
+        Ok(TlogWriter::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> TlogWriter<W> {
+    pub fn new(writer: W) -> TlogWriter<W> {
+        TlogWriter { writer: writer }
+    }
+
+    /// Append a frame, timestamped with its receive time or else the current time.
+    ///
+    /// The frame is framed again from its header, version and message, so a signature
+    /// it was received with is not kept.
+    pub fn write_frame(&mut self, frame: &MavFrame) -> io::Result<()> {
+        let time = match frame.received {
+            Some(received) => received.wall_clock,
+            None => SystemTime::now(),
+        };
+        let mut buf = Vec::new();
+        try!(buf.write_u64::<BigEndian>(to_tlog_timestamp(time)));
+        try!(write_signed(&mut buf, frame.version, frame.header, &frame.msg, None));
+        self.writer.write_all(&buf)
+    }
+
+    /// Append a raw frame byte for byte, timestamped with `time`
+    pub fn write_raw(&mut self, time: SystemTime, frame: &RawFrame) -> io::Result<()> {
+        let mut buf = Vec::new();
+        try!(buf.write_u64::<BigEndian>(to_tlog_timestamp(time)));
+        try!(write_raw(&mut buf, frame));
+        self.writer.write_all(&buf)
+    }
+
+    /// Record every frame received on a connection until it fails.
+    ///
+    /// The error that ended the recording is returned once the recorded frames are flushed.
+    pub fn record<C: MavConnection + ?Sized>(&mut self, conn: &C) -> io::Result<()> {
+        let result = loop {
+            match conn.recv_frame() {
+                Ok(frame) => try!(self.write_frame(&frame)),
+                Err(e) => break Err(e),
+            }
+        };
+        try!(self.flush());
+        result
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Get back the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use common::{HEARTBEAT_DATA, PING_DATA};
+    use {Header, MavlinkVersion};
+
+    fn frame(sequence: u8, version: MavlinkVersion, msg: MavMessage, wall_clock: SystemTime) -> MavFrame {
+        MavFrame {
+            header: Header {
+                sequence: sequence,
+                system_id: 1,
+                component_id: 1,
+            },
+            version: version,
+            msg: msg,
+            received: Some(ReceiveTime {
+                monotonic: Instant::now(),
+                wall_clock: wall_clock,
+            }),
+        }
+    }
+
+    #[test]
+    fn write_and_replay() {
+        let start = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_000);
+        let frames = vec![
+            frame(0, MavlinkVersion::V1, MavMessage::HEARTBEAT(HEARTBEAT_DATA::default()), start),
+            frame(1, MavlinkVersion::V2, MavMessage::PING(PING_DATA::default()), start + Duration::new(0, 1_500_000)),
+            frame(2, MavlinkVersion::V2, MavMessage::HEARTBEAT(HEARTBEAT_DATA::default()), start + Duration::from_secs(2)),
+        ];
+        let unknown = RawFrame {
+            header: frames[0].header,
+            version: MavlinkVersion::V2,
+            incompat_flags: 0,
+            compat_flags: 0,
+            msgid: 0xFFFF00,
+            payload: vec![1, 2, 3],
+            checksum: 0,
+            signature: None,
+        };
+
+        let mut writer = TlogWriter::new(Vec::new());
+        writer.write_frame(&frames[0]).unwrap();
+        writer.write_frame(&frames[1]).unwrap();
+        writer.write_raw(start + Duration::from_secs(1), &unknown).unwrap();
+        writer.write_frame(&frames[2]).unwrap();
+        let path = env::temp_dir().join(format!("mavlink-tlog-test-{}.tlog", ::std::process::id()));
+        fs::write(&path, writer.into_inner()).unwrap();
+
+        let replay = TlogFile::open(&path, None);
+        fs::remove_file(&path).unwrap();
+        let replay = replay.unwrap();
+        for expected in frames.iter() {
+            let replayed = replay.recv_frame().unwrap();
+            assert_eq!(
+                (replayed.header, replayed.version, &replayed.msg),
+                (expected.header, expected.version, &expected.msg)
+            );
+            assert_eq!(replayed.received.unwrap().wall_clock, expected.received.unwrap().wall_clock);
+        }
+        // the frame outside the dialect is skipped
+        assert_eq!(replay.read_error_counts().unknown_message, 1);
+        assert_eq!(replay.recv_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}